@@ -0,0 +1,515 @@
+// What a rom does before running it: its control-flow graph
+//
+// Code is found by following the flow from 0x200 the way the CPU would run
+// it: jumps, calls, skips and returns, with the same opcodes run_opcode
+// knows. What is never reached is probably data. Bnnn jumps somewhere that
+// depends on V0, so the code behind a computed jump isn't followed and can
+// show up as unreachable.
+//
+// Which address I holds is followed too where it's a constant, so stores
+// with Fx55 and Fx33 that land on code are flagged as self-modifying.
+use crate::disasm;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Write};
+use std::ops::Range;
+
+const START: u16 = 0x200;
+const RAM_SIZE: usize = 4096;
+const ADDRESS_MASK: u16 = 0x0FFF;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    Next,   // on to the next instruction
+    Jump,   // 1nnn
+    Skip,   // the instruction after next, when a skip is taken
+    Call,   // 2nnn, into the subroutine
+    Return, // from a call to the instruction after it, once the subroutine returns
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edge {
+    pub to: u16,
+    pub kind: EdgeKind,
+}
+
+// Straight-line code: only the first instruction is jumped to, only the last one branches
+#[derive(Debug, Clone, PartialEq)]
+pub struct BasicBlock {
+    pub start: u16,
+    pub instructions: Vec<(u16, u16)>, // address and opcode
+    pub edges: Vec<Edge>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Subroutine {
+    pub entry: u16,
+    pub callers: Vec<u16>, // addresses of the calls
+    pub blocks: Vec<u16>,  // starts of the blocks run from it before it returns
+}
+
+// A store to memory that holds code
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodeWrite {
+    pub address: u16,
+    pub opcode: u16,
+    pub target: Range<u16>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Analysis {
+    pub blocks: BTreeMap<u16, BasicBlock>,
+    pub subroutines: BTreeMap<u16, Subroutine>,
+    pub loops: Vec<(u16, u16)>,        // (block jumping back, loop header)
+    pub computed_jumps: Vec<u16>,      // Bnnn
+    pub invalid: Vec<u16>,             // reached, but the CPU would stop with an error
+    pub self_modifying: Vec<CodeWrite>,
+    pub unreachable: Vec<Range<u16>>,  // bytes of the rom no reached instruction is made of
+}
+
+// Where the CPU can go after an instruction
+enum Flow {
+    Next,
+    Jump(u16),
+    Skip,
+    Call(u16),
+    Return,
+    Computed,
+    Invalid,
+}
+
+// The same opcodes run_opcode runs, which is more than the disassembler names
+fn flow(opcode: u16) -> Flow {
+    let nn = opcode & 0xFF;
+    let nnn = opcode & 0xFFF;
+    match (opcode >> 12, opcode & 0xF) {
+        (0x0, _) if nn == 0xE0 => Flow::Next,
+        (0x0, _) if nn == 0xEE => Flow::Return,
+        (0x1, _) => Flow::Jump(nnn),
+        (0x2, _) => Flow::Call(nnn),
+        (0x3 | 0x4 | 0x5 | 0x9, _) => Flow::Skip,
+        (0x6 | 0x7 | 0xA | 0xC | 0xD, _) => Flow::Next,
+        (0x8, 0x0..=0x7 | 0xE) => Flow::Next,
+        (0xB, _) => Flow::Computed,
+        (0xE, _) if nn == 0x9E || nn == 0xA1 => Flow::Skip,
+        (0xF, _) if matches!(nn, 0x07 | 0x0A | 0x15 | 0x18 | 0x1E | 0x29 | 0x33 | 0x55 | 0x65) => Flow::Next,
+        _ => Flow::Invalid,
+    }
+}
+
+fn next(address: u16) -> u16 {
+    (address + 2) & ADDRESS_MASK
+}
+
+pub fn analyze(rom: &[u8]) -> Analysis {
+    // Memory as the CPU sees it after loading the rom, the fonts are no code
+    let mut ram = [0u8; RAM_SIZE];
+    let len = rom.len().min(RAM_SIZE - START as usize);
+    ram[START as usize..START as usize + len].copy_from_slice(&rom[..len]);
+    let opcode_at = |address: u16| {
+        let a = address as usize;
+        (a + 1 < RAM_SIZE).then(|| u16::from_be_bytes([ram[a], ram[a + 1]]))
+    };
+
+    // Every reachable instruction, and where blocks have to start
+    let mut reached: BTreeMap<u16, Option<u16>> = BTreeMap::new(); // address -> opcode, None past the end of memory
+    let mut leaders = BTreeSet::from([START]);
+    let mut calls: BTreeMap<u16, Vec<u16>> = BTreeMap::new();
+    let mut todo = vec![START];
+    while let Some(address) = todo.pop() {
+        if reached.contains_key(&address) {
+            continue;
+        }
+        let opcode = opcode_at(address);
+        reached.insert(address, opcode);
+        let targets = match opcode.map_or(Flow::Invalid, flow) {
+            Flow::Next => {
+                todo.push(next(address));
+                continue;
+            }
+            Flow::Jump(target) => vec![target],
+            Flow::Skip => vec![next(address), next(next(address))],
+            Flow::Call(target) => {
+                calls.entry(target).or_default().push(address);
+                vec![target, next(address)]
+            }
+            Flow::Return | Flow::Computed | Flow::Invalid => vec![],
+        };
+        // What comes after a branch starts a block of its own
+        leaders.insert(next(address));
+        leaders.extend(targets.iter().copied());
+        todo.extend(targets);
+    }
+
+    // Cut the instructions into blocks at the leaders and branches
+    let mut blocks = BTreeMap::new();
+    let mut computed_jumps = vec![];
+    let mut invalid = vec![];
+    for &start in leaders.iter().filter(|l| reached.contains_key(l)) {
+        let mut instructions = vec![];
+        let mut address = start;
+        let edges = loop {
+            let Some(opcode) = reached[&address] else {
+                invalid.push(address);
+                break vec![];
+            };
+            instructions.push((address, opcode));
+            let edge = |to, kind| Edge { to, kind };
+            match flow(opcode) {
+                Flow::Next => {}
+                Flow::Jump(target) => break vec![edge(target, EdgeKind::Jump)],
+                Flow::Skip => break vec![edge(next(address), EdgeKind::Next), edge(next(next(address)), EdgeKind::Skip)],
+                Flow::Call(target) => break vec![edge(target, EdgeKind::Call), edge(next(address), EdgeKind::Return)],
+                Flow::Return => break vec![],
+                Flow::Computed => {
+                    computed_jumps.push(address);
+                    break vec![];
+                }
+                Flow::Invalid => {
+                    invalid.push(address);
+                    break vec![];
+                }
+            }
+            address = next(address);
+            if leaders.contains(&address) {
+                break vec![edge(address, EdgeKind::Next)];
+            }
+        };
+        blocks.insert(start, BasicBlock { start, instructions, edges });
+    }
+    computed_jumps.sort();
+    computed_jumps.dedup();
+    invalid.sort();
+    invalid.dedup();
+
+    let subroutines = calls
+        .into_iter()
+        .filter(|(entry, _)| blocks.contains_key(entry))
+        .map(|(entry, mut callers)| {
+            callers.sort();
+            let blocks = reachable(&blocks, entry).into_iter().collect();
+            (entry, Subroutine { entry, callers, blocks })
+        })
+        .collect();
+    let loops = back_edges(&blocks, &subroutines);
+    let self_modifying = code_writes(&blocks, &reached);
+    let unreachable = unreachable(&reached, len);
+    Analysis {
+        blocks,
+        subroutines,
+        loops,
+        computed_jumps,
+        invalid,
+        self_modifying,
+        unreachable,
+    }
+}
+
+// The edges followed within a subroutine or the main program, i.e. not into calls
+fn local_edges(block: &BasicBlock) -> impl Iterator<Item = u16> + '_ {
+    block.edges.iter().filter(|e| e.kind != EdgeKind::Call).map(|e| e.to)
+}
+
+fn reachable(blocks: &BTreeMap<u16, BasicBlock>, entry: u16) -> BTreeSet<u16> {
+    let mut seen = BTreeSet::new();
+    let mut todo = vec![entry];
+    while let Some(start) = todo.pop() {
+        if let Some(block) = blocks.get(&start) {
+            if seen.insert(start) {
+                todo.extend(local_edges(block));
+            }
+        }
+    }
+    seen
+}
+
+// Edges back to a block that is still being walked from the program's or a subroutine's entry
+fn back_edges(blocks: &BTreeMap<u16, BasicBlock>, subroutines: &BTreeMap<u16, Subroutine>) -> Vec<(u16, u16)> {
+    let mut loops = BTreeSet::new();
+    for entry in std::iter::once(START).chain(subroutines.keys().copied()) {
+        let mut done = BTreeSet::new();
+        let mut path = BTreeSet::from([entry]);
+        let mut stack: Vec<(u16, Vec<u16>)> = vec![(entry, local_edges(&blocks[&entry]).collect())];
+        while let Some((start, successors)) = stack.last_mut() {
+            let start = *start;
+            let Some(to) = successors.pop() else {
+                path.remove(&start);
+                done.insert(start);
+                stack.pop();
+                continue;
+            };
+            if path.contains(&to) {
+                loops.insert((start, to));
+            } else if !done.contains(&to) {
+                if let Some(block) = blocks.get(&to) {
+                    path.insert(to);
+                    stack.push((to, local_edges(block).collect()));
+                }
+            }
+        }
+    }
+    loops.into_iter().collect()
+}
+
+// What is known about I at some point
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum IValue {
+    Unreached,
+    Known(u16),
+    Varying,
+}
+
+impl IValue {
+    fn join(self, other: IValue) -> IValue {
+        match (self, other) {
+            (IValue::Unreached, value) | (value, IValue::Unreached) => value,
+            (a, b) if a == b => a,
+            _ => IValue::Varying,
+        }
+    }
+}
+
+// I after an instruction. Fx55/Fx65 move it or not depending on the quirks
+fn transfer(i: IValue, opcode: u16) -> IValue {
+    match (opcode >> 12, opcode & 0xFF) {
+        (0xA, _) => IValue::Known(opcode & 0xFFF),
+        (0xF, 0x1E | 0x29 | 0x55 | 0x65) => IValue::Varying,
+        _ => i,
+    }
+}
+
+// Stores at a constant I that land on reached instructions
+fn code_writes(blocks: &BTreeMap<u16, BasicBlock>, reached: &BTreeMap<u16, Option<u16>>) -> Vec<CodeWrite> {
+    // I at the start of each block, until nothing changes. It's 0 after a reset
+    let mut entry: BTreeMap<u16, IValue> = blocks.keys().map(|&start| (start, IValue::Unreached)).collect();
+    entry.insert(START, IValue::Known(0));
+    let mut todo = vec![START];
+    while let Some(start) = todo.pop() {
+        let block = &blocks[&start];
+        let i = block.instructions.iter().fold(entry[&start], |i, &(_, opcode)| transfer(i, opcode));
+        for edge in &block.edges {
+            // The subroutine may have changed I by the time it returns
+            let i = if edge.kind == EdgeKind::Return { IValue::Varying } else { i };
+            if let Some(&old) = entry.get(&edge.to) {
+                let new = old.join(i);
+                if new != old {
+                    entry.insert(edge.to, new);
+                    todo.push(edge.to);
+                }
+            }
+        }
+    }
+    let code: BTreeSet<u16> = reached.keys().flat_map(|&a| [a, a + 1]).collect();
+    let mut writes = vec![];
+    for block in blocks.values() {
+        let mut i = entry[&block.start];
+        for &(address, opcode) in &block.instructions {
+            let len = match (opcode >> 12, opcode & 0xFF) {
+                (0xF, 0x55) => (opcode >> 8 & 0xF) + 1,
+                (0xF, 0x33) => 3,
+                _ => 0,
+            };
+            if let IValue::Known(target) = i {
+                let target = target..target + len;
+                if len > 0 && target.clone().any(|a| code.contains(&a)) {
+                    writes.push(CodeWrite { address, opcode, target });
+                }
+            }
+            i = transfer(i, opcode);
+        }
+    }
+    writes.sort_by_key(|w| w.address);
+    writes
+}
+
+// The rom's bytes outside every reached instruction, as ranges of addresses
+fn unreachable(reached: &BTreeMap<u16, Option<u16>>, len: usize) -> Vec<Range<u16>> {
+    let mut covered = vec![false; len];
+    for &address in reached.keys() {
+        for a in [address, address + 1] {
+            if let Some(offset) = (a as usize).checked_sub(START as usize) {
+                if offset < len {
+                    covered[offset] = true;
+                }
+            }
+        }
+    }
+    let mut ranges: Vec<Range<u16>> = vec![];
+    for (offset, _) in covered.iter().enumerate().filter(|(_, &c)| !c) {
+        let address = START + offset as u16;
+        match ranges.last_mut() {
+            Some(range) if range.end == address => range.end += 1,
+            _ => ranges.push(address..address + 1),
+        }
+    }
+    ranges
+}
+
+impl Analysis {
+    // The graph for Graphviz, e.g. `dot -Tsvg`. Subroutines are boxed in clusters, loops go back in
+    // blue, computed jumps and stores into code are highlighted
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph rom {\n    node [shape=box, fontname=\"monospace\"];\n");
+        // A block shared by subroutines goes in the first one's cluster
+        let mut owner = BTreeMap::new();
+        for sub in self.subroutines.values() {
+            for &start in &sub.blocks {
+                owner.entry(start).or_insert(sub.entry);
+            }
+        }
+        let writers: BTreeSet<u16> = self.self_modifying.iter().map(|w| w.address).collect();
+        let node = |block: &BasicBlock| {
+            let mut label = String::new();
+            for &(address, opcode) in &block.instructions {
+                write!(label, "{:03X}  {}\\l", address, disasm::disassemble(opcode)).unwrap();
+            }
+            let last = block.instructions.last().map(|&(a, _)| a);
+            let style = if block.instructions.iter().any(|(a, _)| writers.contains(a)) {
+                ", style=filled, fillcolor=\"#ffcccc\""
+            } else if last.is_some_and(|a| self.computed_jumps.contains(&a)) {
+                ", style=filled, fillcolor=\"#ffffcc\""
+            } else if last.is_some_and(|a| self.invalid.contains(&a)) || block.instructions.is_empty() {
+                ", style=filled, fillcolor=\"#dddddd\""
+            } else {
+                ""
+            };
+            format!("n{:03X} [label=\"{}\"{}];", block.start, label, style)
+        };
+        for block in self.blocks.values().filter(|b| !owner.contains_key(&b.start)) {
+            writeln!(dot, "    {}", node(block)).unwrap();
+        }
+        for sub in self.subroutines.values() {
+            writeln!(dot, "    subgraph cluster_{:03X} {{\n        label=\"sub {:#05X}\";", sub.entry, sub.entry).unwrap();
+            for block in sub.blocks.iter().filter(|s| owner[s] == sub.entry).map(|s| &self.blocks[s]) {
+                writeln!(dot, "        {}", node(block)).unwrap();
+            }
+            dot.push_str("    }\n");
+        }
+        for block in self.blocks.values() {
+            for edge in block.edges.iter().filter(|e| self.blocks.contains_key(&e.to)) {
+                let mut attributes = match edge.kind {
+                    EdgeKind::Next | EdgeKind::Jump => vec![],
+                    EdgeKind::Skip => vec!["label=\"skip\""],
+                    EdgeKind::Call => vec!["style=dashed", "label=\"call\""],
+                    EdgeKind::Return => vec!["style=dotted"],
+                };
+                if self.loops.contains(&(block.start, edge.to)) {
+                    attributes.push("color=blue");
+                }
+                let attributes = if attributes.is_empty() { String::new() } else { format!(" [{}]", attributes.join(", ")) };
+                writeln!(dot, "    n{:03X} -> n{:03X}{};", block.start, edge.to, attributes).unwrap();
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+fn addresses(addresses: impl Iterator<Item = u16>) -> String {
+    addresses.map(|a| format!("{:#05X}", a)).collect::<Vec<_>>().join(", ")
+}
+
+// A summary for reading
+impl fmt::Display for Analysis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let instructions: usize = self.blocks.values().map(|b| b.instructions.len()).sum();
+        writeln!(f, "Blocks          {}", self.blocks.len())?;
+        writeln!(f, "Instructions    {}", instructions)?;
+        writeln!(f, "Subroutines     {}", self.subroutines.len())?;
+        for sub in self.subroutines.values() {
+            writeln!(f, "  {:#05X}  {} blocks, called from {}", sub.entry, sub.blocks.len(), addresses(sub.callers.iter().copied()))?;
+        }
+        writeln!(f, "Loops           {}", self.loops.len())?;
+        for &(from, header) in &self.loops {
+            writeln!(f, "  {:#05X}  back from {:#05X}", header, from)?;
+        }
+        writeln!(f, "Computed jumps  {}", self.computed_jumps.len())?;
+        for &address in &self.computed_jumps {
+            writeln!(f, "  {:#05X}", address)?;
+        }
+        writeln!(f, "Invalid         {}", self.invalid.len())?;
+        for &address in &self.invalid {
+            writeln!(f, "  {:#05X}", address)?;
+        }
+        writeln!(f, "Self-modifying  {}", self.self_modifying.len())?;
+        for write in &self.self_modifying {
+            let (start, end) = (write.target.start, write.target.end - 1);
+            writeln!(f, "  {:#05X}  {} writes {:#05X}-{:#05X}", write.address, disasm::disassemble(write.opcode), start, end)?;
+        }
+        let bytes: usize = self.unreachable.iter().map(|r| r.len()).sum();
+        writeln!(f, "Unreachable     {} bytes", bytes)?;
+        for range in &self.unreachable {
+            writeln!(f, "  {:#05X}-{:#05X}", range.start, range.end - 1)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    const PROGRAM: &str = "
+            CLS
+        loop:
+            CALL draw
+            SE V0, 5
+            JP loop
+            LD I, patch
+            LD V0, 0x12
+            LD [I], V0
+        patch:
+            JP V0, 0x300
+        draw:
+            LD I, sprite
+            DRW V1, V2, 1
+            ADD V1, 1
+            RET
+        sprite:
+            DB 0xFF, 0x00
+        ";
+    #[test]
+    fn test_analyze() {
+        let analysis = analyze(&asm::assemble(PROGRAM).unwrap());
+        let starts: Vec<u16> = analysis.blocks.keys().copied().collect();
+        assert_eq!(starts, [0x200, 0x202, 0x204, 0x206, 0x208, 0x210]);
+        assert_eq!(analysis.blocks[&0x208].instructions.len(), 4);
+        assert_eq!(
+            analysis.blocks[&0x202].edges,
+            [Edge { to: 0x210, kind: EdgeKind::Call }, Edge { to: 0x204, kind: EdgeKind::Return }]
+        );
+        assert_eq!(analysis.blocks[&0x204].edges[1], Edge { to: 0x208, kind: EdgeKind::Skip });
+        let draw = &analysis.subroutines[&0x210];
+        assert_eq!((draw.callers.clone(), draw.blocks.clone()), (vec![0x202], vec![0x210]));
+        assert_eq!(analysis.loops, [(0x206, 0x202)]);
+        assert_eq!(analysis.computed_jumps, [0x20E]);
+        assert!(analysis.invalid.is_empty());
+        // LD [I], V0 at 0x20C stores over the Bnnn at 0x20E
+        assert_eq!(analysis.self_modifying.len(), 1);
+        assert_eq!((analysis.self_modifying[0].address, analysis.self_modifying[0].target.clone()), (0x20C, 0x20E..0x20F));
+        assert_eq!(analysis.unreachable, vec![0x218..0x21A]);
+    }
+    #[test]
+    fn test_report_and_dot() {
+        let analysis = analyze(&asm::assemble(PROGRAM).unwrap());
+        let report = analysis.to_string();
+        assert!(report.contains("Subroutines     1\n  0x210  1 blocks, called from 0x202\n"));
+        assert!(report.contains("Loops           1\n  0x202  back from 0x206\n"));
+        assert!(report.contains("  0x20C  LD [I], V0 writes 0x20E-0x20E\n"));
+        assert!(report.contains("Unreachable     2 bytes\n  0x218-0x219\n"));
+        let dot = analysis.to_dot();
+        assert!(dot.starts_with("digraph rom {"));
+        assert!(dot.contains("    subgraph cluster_210 {\n        label=\"sub 0x210\";\n        n210 [label=\"210  LD I, 0x218\\l"));
+        assert!(dot.contains("n202 -> n210 [style=dashed, label=\"call\"];"));
+        assert!(dot.contains("n206 -> n202 [color=blue];"));
+        assert!(dot.contains("n204 -> n208 [label=\"skip\"];"));
+        assert!(dot.trim_end().ends_with('}'));
+    }
+    #[test]
+    fn test_invalid_and_edge_of_memory() {
+        // Jumps to the last address, where there's no room for an opcode, and into zeroed memory
+        let analysis = analyze(&[0x30, 0x00, 0x1F, 0xFF, 0x13, 0x00]);
+        assert_eq!(analysis.invalid, [0x300, 0xFFF]);
+        assert!(analysis.unreachable.is_empty());
+        assert!(analyze(&[]).invalid == [0x200]);
+    }
+}
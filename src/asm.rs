@@ -0,0 +1,228 @@
+// A small assembler for the mnemonics the disassembler prints
+//
+//     loop:               ; labels end in a colon
+//         LD V0, 0x05     ; numbers are decimal, 0x hex or 0b binary
+//         DRW V0, V1, 5
+//         JP loop
+//     sprite:
+//         DB 0xF0, 0x90, 0xF0
+//
+// Programs start at 0x200. Mnemonics and registers don't care about case,
+// labels do.
+use std::collections::HashMap;
+
+const START: usize = 0x200;
+const END: usize = 0x1000; // the end of the memory, where a program has to stop
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    V(u16),
+    I,
+    IndirectI, // [I]
+    Dt,
+    St,
+    K,
+    F,
+    B,
+    Value(String), // a number or a label, resolved in the second pass
+}
+
+struct Line<'a> {
+    number: usize,
+    mnemonic: String,
+    operands: Vec<&'a str>,
+}
+
+// The bytes of the program, or the first error with its line number
+pub fn assemble(source: &str) -> Result<Vec<u8>, String> {
+    // First pass: where every label is
+    let mut labels: HashMap<&str, usize> = HashMap::new();
+    let mut lines = vec![];
+    let mut address = START;
+    for (idx, text) in source.lines().enumerate() {
+        let number = idx + 1;
+        let mut text = text.split(';').next().unwrap().trim();
+        if let Some((label, rest)) = text.split_once(':') {
+            let label = label.trim();
+            if label.is_empty() || !label.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                return Err(format!("line {}: invalid label: {}", number, label));
+            }
+            // A label that reads as a register or a number could never be used
+            if !matches!(parse_operand(label), Operand::Value(_)) || label.starts_with(|c: char| c.is_ascii_digit()) {
+                return Err(format!("line {}: {} can't be a label, it's an operand", number, label));
+            }
+            if labels.insert(label, address).is_some() {
+                return Err(format!("line {}: {} is defined twice", number, label));
+            }
+            text = rest.trim();
+        }
+        if text.is_empty() {
+            continue;
+        }
+        let (mnemonic, operands) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let operands: Vec<&str> = match operands.trim() {
+            "" => vec![],
+            operands => operands.split(',').map(str::trim).collect(),
+        };
+        let mnemonic = mnemonic.to_uppercase();
+        address += match mnemonic.as_str() {
+            "DB" => operands.len(),
+            "DW" => operands.len() * 2,
+            _ => 2,
+        };
+        if address > END {
+            return Err(format!("line {}: the program doesn't fit in memory, it ends at {:#X}", number, address));
+        }
+        lines.push(Line { number, mnemonic, operands });
+    }
+    // Second pass: the bytes
+    let mut bytes = vec![];
+    for line in &lines {
+        let operands: Vec<Operand> = line.operands.iter().map(|o| parse_operand(o)).collect();
+        let value = |operand: &Operand, max: usize| resolve(operand, &labels, max);
+        let result = match line.mnemonic.as_str() {
+            "DB" => operands.iter().try_for_each(|o| {
+                bytes.push(value(o, 0xFF)? as u8);
+                Ok(())
+            }),
+            "DW" => operands.iter().try_for_each(|o| {
+                bytes.extend(value(o, 0xFFFF)?.to_be_bytes());
+                Ok(())
+            }),
+            mnemonic => encode(mnemonic, &operands, &value).map(|opcode| bytes.extend(opcode.to_be_bytes())),
+        };
+        result.map_err(|e| format!("line {}: {}", line.number, e))?;
+    }
+    Ok(bytes)
+}
+
+fn parse_operand(s: &str) -> Operand {
+    let upper = s.to_uppercase();
+    match upper.as_str() {
+        "I" => Operand::I,
+        "[I]" => Operand::IndirectI,
+        "DT" => Operand::Dt,
+        "ST" => Operand::St,
+        "K" => Operand::K,
+        "F" => Operand::F,
+        "B" => Operand::B,
+        _ => match upper.strip_prefix('V').map(|r| u16::from_str_radix(r, 16)) {
+            Some(Ok(x)) if x <= 0xF && upper.len() == 2 => Operand::V(x),
+            _ => Operand::Value(s.to_string()),
+        },
+    }
+}
+
+// A number or label, which has to fit in max
+fn resolve(operand: &Operand, labels: &HashMap<&str, usize>, max: usize) -> Result<u16, String> {
+    let Operand::Value(s) = operand else {
+        return Err(format!("expected a number, got {:?}", operand));
+    };
+    let lower = s.to_lowercase();
+    let value = if let Some(hex) = lower.strip_prefix("0x") {
+        usize::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        usize::from_str_radix(binary, 2).ok()
+    } else if s.starts_with(|c: char| c.is_ascii_digit()) {
+        s.parse().ok()
+    } else {
+        Some(*labels.get(s.as_str()).ok_or_else(|| format!("unknown label: {}", s))?)
+    };
+    match value {
+        Some(value) if value <= max => Ok(value as u16),
+        Some(value) => Err(format!("{} doesn't fit in {:#X}", value, max)),
+        None => Err(format!("invalid number: {}", s)),
+    }
+}
+
+fn encode(
+    mnemonic: &str,
+    operands: &[Operand],
+    value: &dyn Fn(&Operand, usize) -> Result<u16, String>,
+) -> Result<u16, String> {
+    use Operand::*;
+    let xy = |x: u16, y: u16| x << 8 | y << 4;
+    let opcode = match (mnemonic, operands) {
+        ("CLS", []) => 0x00E0,
+        ("RET", []) => 0x00EE,
+        ("JP", [V(0), addr]) => 0xB000 | value(addr, 0xFFF)?,
+        ("JP", [addr]) => 0x1000 | value(addr, 0xFFF)?,
+        ("CALL", [addr]) => 0x2000 | value(addr, 0xFFF)?,
+        ("SE", [V(x), V(y)]) => 0x5000 | xy(*x, *y),
+        ("SE", [V(x), byte]) => 0x3000 | x << 8 | value(byte, 0xFF)?,
+        ("SNE", [V(x), V(y)]) => 0x9000 | xy(*x, *y),
+        ("SNE", [V(x), byte]) => 0x4000 | x << 8 | value(byte, 0xFF)?,
+        ("LD", [V(x), V(y)]) => 0x8000 | xy(*x, *y),
+        ("LD", [V(x), Dt]) => 0xF007 | x << 8,
+        ("LD", [V(x), K]) => 0xF00A | x << 8,
+        ("LD", [V(x), IndirectI]) => 0xF065 | x << 8,
+        ("LD", [V(x), byte]) => 0x6000 | x << 8 | value(byte, 0xFF)?,
+        ("LD", [I, addr]) => 0xA000 | value(addr, 0xFFF)?,
+        ("LD", [Dt, V(x)]) => 0xF015 | x << 8,
+        ("LD", [St, V(x)]) => 0xF018 | x << 8,
+        ("LD", [F, V(x)]) => 0xF029 | x << 8,
+        ("LD", [B, V(x)]) => 0xF033 | x << 8,
+        ("LD", [IndirectI, V(x)]) => 0xF055 | x << 8,
+        ("ADD", [V(x), V(y)]) => 0x8004 | xy(*x, *y),
+        ("ADD", [V(x), byte]) => 0x7000 | x << 8 | value(byte, 0xFF)?,
+        ("ADD", [I, V(x)]) => 0xF01E | x << 8,
+        ("OR", [V(x), V(y)]) => 0x8001 | xy(*x, *y),
+        ("AND", [V(x), V(y)]) => 0x8002 | xy(*x, *y),
+        ("XOR", [V(x), V(y)]) => 0x8003 | xy(*x, *y),
+        ("SUB", [V(x), V(y)]) => 0x8005 | xy(*x, *y),
+        ("SHR", [V(x), V(y)]) => 0x8006 | xy(*x, *y),
+        ("SHR", [V(x)]) => 0x8006 | xy(*x, *x),
+        ("SUBN", [V(x), V(y)]) => 0x8007 | xy(*x, *y),
+        ("SHL", [V(x), V(y)]) => 0x800E | xy(*x, *y),
+        ("SHL", [V(x)]) => 0x800E | xy(*x, *x),
+        ("RND", [V(x), byte]) => 0xC000 | x << 8 | value(byte, 0xFF)?,
+        ("DRW", [V(x), V(y), n]) => 0xD000 | xy(*x, *y) | value(n, 0xF)?,
+        ("SKP", [V(x)]) => 0xE09E | x << 8,
+        ("SKNP", [V(x)]) => 0xE0A1 | x << 8,
+        _ => return Err(format!("invalid instruction: {} with {} operands", mnemonic, operands.len())),
+    };
+    Ok(opcode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm;
+    #[test]
+    fn test_assemble() {
+        let source = "start: CLS ; clear\n  ld v0, 0x05\nloop:\n  DRW V0, V1, 5\n  JP loop\n  LD I, sprite\n\
+                      sprite: DB 0xF0, 0b1001, 255\n  DW 0x1234\n";
+        let bytes = assemble(source).unwrap();
+        assert_eq!(
+            bytes,
+            [0x00, 0xE0, 0x60, 0x05, 0xD0, 0x15, 0x12, 0x04, 0xA2, 0x0A, 0xF0, 0x09, 0xFF, 0x12, 0x34]
+        );
+    }
+    #[test]
+    fn test_errors() {
+        assert_eq!(assemble("CLS\nJP nowhere").unwrap_err(), "line 2: unknown label: nowhere");
+        assert!(assemble("LD V0, 256").unwrap_err().starts_with("line 1:"));
+        assert!(assemble("MOV V0, V1").is_err());
+        assert!(assemble("a: CLS\na: CLS").is_err());
+        assert!(assemble("DRW V0, V1").is_err());
+        for label in ["b", "i", "dt", "va", "V0", "2nd"] {
+            let error = assemble(&format!("{}: CLS\nJP {}", label, label)).unwrap_err();
+            assert_eq!(error, format!("line 1: {} can't be a label, it's an operand", label));
+        }
+        assert!(assemble("vb1: CLS\nJP vb1").is_ok());
+        // 3584 bytes fill the memory from 0x200, one more doesn't fit
+        let fits = "DB 0\n".repeat(3584);
+        assert_eq!(assemble(&fits).unwrap().len(), 3584);
+        assert_eq!(
+            assemble(&(fits + "CLS")).unwrap_err(),
+            "line 3585: the program doesn't fit in memory, it ends at 0x1002"
+        );
+    }
+    #[test]
+    fn test_every_opcode_round_trips() {
+        for opcode in 0..=0xFFFF_u16 {
+            let bytes = assemble(&disasm::disassemble(opcode)).unwrap();
+            assert_eq!(bytes, opcode.to_be_bytes(), "{:#06X}", opcode);
+        }
+    }
+}
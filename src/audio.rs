@@ -0,0 +1,320 @@
+// Sound output for the sound timer
+//
+// The CPU only tells us whether ST is nonzero, the rest happens here: a tone
+// generator that fades in and out so starting and stopping doesn't click,
+// and a few sinks to send the samples to.
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+pub const SAMPLE_RATE: u32 = 44100;
+// 44100 divides evenly by 60, so every emulated frame is exactly this many samples
+pub const SAMPLES_PER_FRAME: usize = (SAMPLE_RATE / 60) as usize;
+const RAMP_TIME: f32 = 0.005; // fade in/out over 5ms
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Waveform {
+    Square,
+    Triangle,
+    Sawtooth,
+    Sine,
+}
+
+impl FromStr for Waveform {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "square" => Ok(Waveform::Square),
+            "triangle" => Ok(Waveform::Triangle),
+            "sawtooth" => Ok(Waveform::Sawtooth),
+            "sine" => Ok(Waveform::Sine),
+            _ => Err(format!("Unknown waveform: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ToneSettings {
+    pub frequency: f32, // in Hz
+    pub volume: f32,    // 0.0 - 1.0
+    pub waveform: Waveform,
+}
+
+impl Default for ToneSettings {
+    fn default() -> Self {
+        ToneSettings {
+            frequency: 440.0,
+            volume: 0.25,
+            waveform: Waveform::Square,
+        }
+    }
+}
+
+pub struct ToneGenerator {
+    settings: ToneSettings,
+    sample_rate: u32,
+    phase: f32, // position inside the current period, 0.0 - 1.0
+    gain: f32,  // current envelope, ramps towards 1.0 while playing
+    playing: bool,
+    muted: bool,
+}
+
+impl ToneGenerator {
+    pub fn new(settings: ToneSettings, sample_rate: u32) -> Self {
+        ToneGenerator {
+            settings,
+            sample_rate,
+            phase: 0.0,
+            gain: 0.0,
+            playing: false,
+            muted: false,
+        }
+    }
+    pub fn set_playing(&mut self, playing: bool) {
+        self.playing = playing;
+    }
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+    }
+    pub fn is_muted(&self) -> bool {
+        self.muted
+    }
+    pub fn fill(&mut self, out: &mut [f32]) {
+        let target = if self.playing && !self.muted { 1.0 } else { 0.0 };
+        let ramp_step = 1.0 / (RAMP_TIME * self.sample_rate as f32);
+        let phase_step = self.settings.frequency / self.sample_rate as f32;
+        for sample in out.iter_mut() {
+            // Move the envelope towards the target instead of jumping to it
+            if self.gain < target {
+                self.gain = (self.gain + ramp_step).min(target);
+            } else if self.gain > target {
+                self.gain = (self.gain - ramp_step).max(target);
+            }
+            *sample = wave(self.settings.waveform, self.phase) * self.gain * self.settings.volume;
+            self.phase = (self.phase + phase_step) % 1.0;
+        }
+    }
+}
+
+fn wave(waveform: Waveform, phase: f32) -> f32 {
+    match waveform {
+        Waveform::Square => {
+            if phase < 0.5 {
+                1.0
+            } else {
+                -1.0
+            }
+        }
+        Waveform::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+        Waveform::Sawtooth => 2.0 * phase - 1.0,
+        Waveform::Sine => (2.0 * PI * phase).sin(),
+    }
+}
+
+pub trait AudioSink {
+    // Whether the sound timer is running, polled by the frontend
+    fn set_beep(&mut self, on: bool);
+    // Called once per emulated 60 Hz frame, sinks that aren't real time render their samples here.
+    // XO-CHIP pattern audio will be handed over at the same point once the CPU supports it.
+    fn end_frame(&mut self) {}
+    fn set_muted(&mut self, muted: bool);
+    fn is_muted(&self) -> bool;
+    // Flush whatever is buffered, called when the emulator exits
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Throws the sound away, for machines without a sound card
+#[derive(Default)]
+pub struct NullSink {
+    muted: bool,
+}
+
+impl AudioSink for NullSink {
+    fn set_beep(&mut self, _on: bool) {}
+    fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+    }
+    fn is_muted(&self) -> bool {
+        self.muted
+    }
+}
+
+// Renders the sound to a mono 16-bit WAV file
+pub struct WavSink {
+    generator: ToneGenerator,
+    writer: WavWriter,
+    buffer: Vec<f32>,
+    error: Option<io::Error>, // the first write that failed, writing stops there
+}
+
+impl WavSink {
+    pub fn create(path: &Path, settings: ToneSettings) -> io::Result<Self> {
+        Ok(WavSink {
+            generator: ToneGenerator::new(settings, SAMPLE_RATE),
+            writer: WavWriter::create(path, SAMPLE_RATE)?,
+            buffer: vec![0.0; SAMPLES_PER_FRAME],
+            error: None,
+        })
+    }
+}
+
+impl AudioSink for WavSink {
+    fn set_beep(&mut self, on: bool) {
+        self.generator.set_playing(on);
+    }
+    fn end_frame(&mut self) {
+        if self.error.is_some() {
+            return;
+        }
+        self.generator.fill(&mut self.buffer);
+        if let Err(e) = self.writer.write(&self.buffer) {
+            self.error = Some(e);
+        }
+    }
+    fn set_muted(&mut self, muted: bool) {
+        self.generator.set_muted(muted);
+    }
+    fn is_muted(&self) -> bool {
+        self.generator.is_muted()
+    }
+    fn finish(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => self.writer.finish(),
+        }
+    }
+}
+
+pub struct WavWriter {
+    file: BufWriter<File>,
+    samples: u32,
+}
+
+impl WavWriter {
+    pub fn create(path: &Path, sample_rate: u32) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        // The sizes are patched in by finish() once we know them
+        file.write_all(b"RIFF")?;
+        file.write_all(&0_u32.to_le_bytes())?;
+        file.write_all(b"WAVEfmt ")?;
+        file.write_all(&16_u32.to_le_bytes())?; // size of the fmt chunk
+        file.write_all(&1_u16.to_le_bytes())?; // PCM
+        file.write_all(&1_u16.to_le_bytes())?; // mono
+        file.write_all(&sample_rate.to_le_bytes())?;
+        file.write_all(&(sample_rate * 2).to_le_bytes())?; // bytes per second
+        file.write_all(&2_u16.to_le_bytes())?; // bytes per sample
+        file.write_all(&16_u16.to_le_bytes())?; // bits per sample
+        file.write_all(b"data")?;
+        file.write_all(&0_u32.to_le_bytes())?;
+        Ok(WavWriter { file, samples: 0 })
+    }
+    pub fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        for &sample in samples {
+            let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.file.write_all(&value.to_le_bytes())?;
+        }
+        self.samples += samples.len() as u32;
+        Ok(())
+    }
+    pub fn finish(&mut self) -> io::Result<()> {
+        let data_size = self.samples * 2;
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&(36 + data_size).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&data_size.to_le_bytes())?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.flush()
+    }
+}
+
+// Plays the sound through SDL2, needs the `sdl` feature and libSDL2
+#[cfg(feature = "sdl")]
+pub struct SdlSink {
+    device: sdl2::audio::AudioDevice<ToneGenerator>,
+    muted: bool, // lock() needs &mut self, so keep a copy for is_muted()
+}
+
+#[cfg(feature = "sdl")]
+impl sdl2::audio::AudioCallback for ToneGenerator {
+    type Channel = f32;
+    fn callback(&mut self, out: &mut [f32]) {
+        self.fill(out);
+    }
+}
+
+#[cfg(feature = "sdl")]
+impl SdlSink {
+    pub fn open(sdl: &sdl2::Sdl, settings: ToneSettings) -> Result<Self, String> {
+        let audio = sdl.audio()?;
+        let spec = sdl2::audio::AudioSpecDesired {
+            freq: Some(SAMPLE_RATE as i32),
+            channels: Some(1),
+            samples: Some(512),
+        };
+        let device = audio.open_playback(None, &spec, |spec| ToneGenerator::new(settings, spec.freq as u32))?;
+        device.resume();
+        Ok(SdlSink { device, muted: false })
+    }
+}
+
+#[cfg(feature = "sdl")]
+impl AudioSink for SdlSink {
+    fn set_beep(&mut self, on: bool) {
+        self.device.lock().set_playing(on);
+    }
+    fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+        self.device.lock().set_muted(muted);
+    }
+    fn is_muted(&self) -> bool {
+        self.muted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_tone_fades_in_and_out() {
+        let mut generator = ToneGenerator::new(ToneSettings::default(), SAMPLE_RATE);
+        let mut out = [0.0; 64];
+        generator.set_playing(true);
+        generator.fill(&mut out);
+        // The first sample starts near silence instead of at full volume
+        assert!(out[0].abs() < 0.01);
+        let mut steady = [0.0; 1024];
+        generator.fill(&mut steady);
+        assert!(steady.iter().any(|s| (s.abs() - 0.25).abs() < 1e-6));
+        generator.set_playing(false);
+        generator.fill(&mut steady);
+        assert!(steady[1023].abs() < 1e-6);
+    }
+    #[test]
+    fn test_muted_tone_is_silent() {
+        let mut generator = ToneGenerator::new(ToneSettings::default(), SAMPLE_RATE);
+        generator.set_muted(true);
+        generator.set_playing(true);
+        let mut out = [1.0; 256];
+        generator.fill(&mut out);
+        assert!(out.iter().all(|&s| s == 0.0));
+    }
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_wav_write_errors_reach_finish() {
+        // Every write to /dev/full fails, once the buffer in front of it is flushed
+        let mut sink = WavSink::create(Path::new("/dev/full"), ToneSettings::default()).unwrap();
+        for _ in 0..60 {
+            sink.end_frame();
+        }
+        assert!(sink.finish().is_err());
+    }
+    #[test]
+    fn test_parse_waveform() {
+        assert_eq!("sine".parse::<Waveform>(), Ok(Waveform::Sine));
+        assert!("noise".parse::<Waveform>().is_err());
+    }
+}
@@ -0,0 +1,134 @@
+// Measuring how fast the core runs, so changes to it can be compared
+//
+// A workload is a rom run straight on a Cpu, without a driver or a frontend,
+// for a number of cycles. Besides roms there are small synthetic programs that
+// each lean on one kind of instruction, written for the assembler.
+use crate::asm;
+use crate::cpu::{Cpu, CpuError, Engine};
+use std::time::{Duration, Instant};
+
+// (name, source) of the synthetic workloads
+const SYNTHETIC: [(&str, &str); 5] = [
+    // Arithmetic and logic on the registers, the straight-line code the recompiler is for
+    (
+        "alu",
+        "loop:
+            ADD V0, 1
+            LD V1, V0
+            ADD V1, V0
+            XOR V2, V1
+            SHR V3, V2
+            SUB V4, V0
+            OR V5, V4
+            SHL V6, V5
+            AND V7, V0
+            RND V8, 0xFF
+            LD I, 0x300
+            ADD I, V0
+            JP loop",
+    ),
+    // Skips, calls and returns
+    (
+        "branch",
+        "loop:
+            ADD V0, 1
+            SE V0, 0
+            SNE V1, 2
+            CALL sub
+            SE V0, V1
+            JP loop
+            JP loop
+        sub:
+            RET",
+    ),
+    // Stores, loads and BCD, which invalidate the cached engines' work
+    (
+        "memory",
+        "loop:
+            ADD V0, 3
+            LD I, buffer
+            LD B, V0
+            LD [I], V7
+            LD V7, [I]
+            JP loop
+        buffer:
+            DB 0, 0, 0, 0, 0, 0, 0, 0",
+    ),
+    // Dxyn with the biggest sprite, moving around the screen
+    (
+        "draw",
+        "   LD I, sprite
+        loop:
+            DRW V0, V1, 15
+            ADD V0, 3
+            ADD V1, 5
+            JP loop
+        sprite:
+            DB 0xFF, 0x81, 0xBD, 0xA5, 0xA5, 0xBD, 0x81, 0xFF
+            DB 0x18, 0x3C, 0x7E, 0xFF, 0x7E, 0x3C, 0x18",
+    ),
+    // 00E0 over and over
+    (
+        "clear",
+        "loop:
+            CLS
+            CLS
+            CLS
+            CLS
+            JP loop",
+    ),
+];
+
+// The synthetic workloads as (name, rom)
+pub fn synthetic() -> Vec<(&'static str, Vec<u8>)> {
+    SYNTHETIC
+        .iter()
+        .map(|&(name, source)| (name, asm::assemble(source).expect("A synthetic workload doesn't assemble")))
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Measurement {
+    pub cycles: u64,
+    pub elapsed: Duration,
+}
+
+impl Measurement {
+    pub fn per_second(&self) -> f64 {
+        self.cycles as f64 / self.elapsed.as_secs_f64().max(f64::MIN_POSITIVE)
+    }
+}
+
+// Run a rom from power-on for at least the given number of cycles, timing only the CPU
+pub fn measure(rom: &[u8], engine: Engine, speed: u32, cycles: u64) -> Result<Measurement, CpuError> {
+    let mut cpu = Cpu::new();
+    cpu.engine = engine;
+    cpu.speed = speed;
+    cpu.load_rom(rom.to_vec())?;
+    let start = Instant::now();
+    let mut ran = 0;
+    while ran < cycles {
+        ran += cpu.emulate_cycles()? as u64;
+    }
+    Ok(Measurement {
+        cycles: ran,
+        elapsed: start.elapsed(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_workloads_run_on_every_engine() {
+        let workloads = synthetic();
+        assert_eq!(workloads.len(), SYNTHETIC.len());
+        for (name, rom) in &workloads {
+            for engine in [Engine::Interpreter, Engine::Cached, Engine::Recompiler] {
+                let measurement = measure(rom, engine, 1000, 10_000).unwrap_or_else(|e| panic!("{}: {}", name, e));
+                assert!(measurement.cycles >= 10_000);
+                assert!(measurement.per_second() > 0.0);
+            }
+        }
+    }
+}
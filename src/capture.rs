@@ -0,0 +1,178 @@
+// Pictures of the screen: screenshots now, recordings build on the same images
+//
+// An image is taken in the active palette after flicker reduction, i.e. the
+// way the frontends draw it, at 1:1 or blown up by a whole number of pixels.
+use crate::cpu::{CHIP_8_HEIGHT, CHIP_8_WIDTH};
+use crate::driver::Driver;
+use crate::palette::{Colour, Palette};
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// 8-bit RGB pixels, row by row
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<[u8; 3]>,
+}
+
+impl Image {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.pixels.concat()
+    }
+    // Opaque 8-bit RGBA, the way textures want it
+    pub fn to_rgba(&self) -> Vec<u8> {
+        self.pixels.iter().flat_map(|&[r, g, b]| [r, g, b, 0xFF]).collect()
+    }
+}
+
+// Every CHIP-8 pixel becomes scale_x by scale_y image pixels
+pub fn capture(driver: &Driver, palette: &Palette, scale_x: usize, scale_y: usize) -> Image {
+    let width = CHIP_8_WIDTH * scale_x;
+    let height = CHIP_8_HEIGHT * scale_y;
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            pixels.push(to_rgb(palette.shade(driver.brightness(x / scale_x, y / scale_y))));
+        }
+    }
+    Image { width, height, pixels }
+}
+
+// The display as text, one line per row with '#' for a lit pixel, like the golden images
+pub fn text(driver: &Driver) -> String {
+    let mut text = String::with_capacity((CHIP_8_WIDTH + 1) * CHIP_8_HEIGHT);
+    for y in 0..CHIP_8_HEIGHT {
+        for x in 0..CHIP_8_WIDTH {
+            text.push(if driver.cpu.display().get(x, y) { '#' } else { '.' });
+        }
+        text.push('\n');
+    }
+    text
+}
+
+pub fn to_rgb(colour: Colour) -> [u8; 3] {
+    let [r, g, b, _] = colour.map(|c| (c * 255.0).round() as u8);
+    [r, g, b]
+}
+
+pub fn write_png(image: &Image, path: &Path) -> io::Result<()> {
+    let file = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(file, image.width as u32, image.height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&image.to_bytes())?;
+    Ok(())
+}
+
+// "20261019-153000" in UTC, so files sort by when they were taken
+pub fn timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    // Days to a civil date, from Howard Hinnant's date algorithms
+    let z = secs / 86400 + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    let time = secs % 86400;
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+// dir/name-timestamp.ext, with a number added if that's taken already.
+// An empty extension is for directories
+pub fn timestamped_path(dir: &Path, name: &str, extension: &str) -> PathBuf {
+    let stem = format!("{}-{}", name, timestamp(SystemTime::now()));
+    let file_name = |stem: String| match extension {
+        "" => stem,
+        _ => format!("{}.{}", stem, extension),
+    };
+    let mut path = dir.join(file_name(stem.clone()));
+    let mut n = 2;
+    while path.exists() {
+        path = dir.join(file_name(format!("{}-{}", stem, n)));
+        n += 1;
+    }
+    path
+}
+
+pub struct ScreenshotSettings {
+    pub dir: PathBuf,
+    pub scale: Option<usize>, // None is the size the frontend draws at, 1 is native
+}
+
+impl ScreenshotSettings {
+    // Save a PNG named after the rom, `drawn` is the pixel size the frontend draws at
+    pub fn save(&self, driver: &Driver, palette: &Palette, drawn: (usize, usize)) -> io::Result<PathBuf> {
+        let (scale_x, scale_y) = self.scale.map_or(drawn, |s| (s, s));
+        let image = capture(driver, palette, scale_x.max(1), scale_y.max(1));
+        std::fs::create_dir_all(&self.dir)?;
+        let name = driver.rom_name().unwrap_or_else(|| "chip8".to_string());
+        let path = timestamped_path(&self.dir, &name, "png");
+        write_png(&image, &path)?;
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::NullSink;
+    use crate::cpu::Cpu;
+    use std::env;
+    use std::time::Duration;
+    #[test]
+    fn test_capture_scales() {
+        let mut driver = Driver::new(Cpu::new(), Box::new(NullSink::default()));
+        // LD I, 0x204; DRW V0, V0, 1 with the sprite 0x40, which lights pixel (1, 0)
+        driver.load_rom(vec![0xA2, 0x04, 0xD0, 0x01, 0x40]).unwrap();
+        driver.step().unwrap();
+        driver.step().unwrap();
+        let image = capture(&driver, &Palette::default(), 2, 3);
+        assert_eq!((image.width, image.height), (128, 96));
+        assert_eq!(image.pixels[0], [0, 0, 0]);
+        // Pixel (1, 0) covers image pixels 2-3 of rows 0-2
+        assert_eq!(image.pixels[2], [255, 255, 255]);
+        assert_eq!(image.pixels[2 * 128 + 3], [255, 255, 255]);
+        assert_eq!(image.pixels[3 * 128 + 3], [0, 0, 0]);
+        let rgba = image.to_rgba();
+        assert_eq!(rgba.len(), 128 * 96 * 4);
+        assert_eq!(rgba[..12], [0, 0, 0, 255, 0, 0, 0, 255, 255, 255, 255, 255]);
+        let text = text(&driver);
+        assert_eq!(text.lines().count(), 32);
+        assert!(text.starts_with(".#.."));
+    }
+    #[test]
+    fn test_timestamp() {
+        assert_eq!(timestamp(UNIX_EPOCH), "19700101-000000");
+        assert_eq!(timestamp(UNIX_EPOCH + Duration::from_secs(1_000_000_000)), "20010909-014640");
+        assert_eq!(timestamp(UNIX_EPOCH + Duration::from_secs(951_782_400)), "20000229-000000");
+    }
+    #[test]
+    fn test_save_screenshot() {
+        let dir = env::temp_dir().join(format!("chip8-screenshots-{}", std::process::id()));
+        let driver = Driver::new(Cpu::new(), Box::new(NullSink::default()));
+        let settings = ScreenshotSettings { dir: dir.clone(), scale: Some(1) };
+        let first = settings.save(&driver, &Palette::default(), (7, 7)).unwrap();
+        let second = settings.save(&driver, &Palette::default(), (7, 7)).unwrap();
+        assert_ne!(first, second);
+        let decoder = png::Decoder::new(File::open(&first).unwrap());
+        let info = decoder.read_info().unwrap().info().clone();
+        assert_eq!((info.width, info.height), (64, 32));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,151 @@
+// User configuration, read from a TOML file such as:
+//
+//     [display]
+//     palette = "amber"
+//     colours = ["#000000", "#33FF66"]
+//     flicker = "or"
+//
+//     [keymap]
+//     preset = "azerty"
+//     keys = { 4 = ["left"], 6 = ["right"], 5 = ["space", "scancode:57"] }
+//
+//     [gamepad]
+//     threshold = 0.6
+//     keys = { 5 = ["a", "b"] }
+//
+//     [screenshots]
+//     dir = "/home/me/Pictures/chip8"
+//     scale = 1
+//
+//     # Settings for one rom, by the SHA-1 of the file (or by its file name)
+//     [roms.e670ac22abbfe46a3bcf98e36ac5a34074c43693]
+//     title = "IBM Logo"
+//     speed = 15
+//     platform = "chip-8"
+//     quirks = "vip"
+//     palette = "amber"
+//     keymap = { keys = { 4 = ["left"], 6 = ["right"] } }
+//     gamepad = { keys = { 4 = ["dpleft"], 6 = ["dpright"], 2 = ["dpup"], 8 = ["dpdown"], 5 = ["a"] } }
+//     flicker = "phosphor"
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub display: DisplayConfig,
+    pub keymap: KeymapConfig,
+    pub gamepad: GamepadConfig,
+    pub screenshots: ScreenshotConfig,
+    pub roms: BTreeMap<String, RomConfig>, // SHA-1 or file name without the extension -> the rom's settings
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DisplayConfig {
+    pub palette: Option<String>,      // name of a built-in palette
+    pub colours: Option<Vec<String>>, // 2 or 4 hex colours, override the palette
+    pub flicker: Option<String>,      // off, vblank, or or phosphor
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScreenshotConfig {
+    pub dir: Option<PathBuf>,
+    pub scale: Option<usize>, // 1 is native, unset is the size on screen
+}
+
+// Settings for one rom, over the ones for all roms
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RomConfig {
+    pub title: Option<String>, // only for whoever reads the file
+    pub speed: Option<u32>,    // instructions per frame
+    pub platform: Option<String>,
+    pub quirks: Option<String>, // a quirk profile, the platform's by default
+    pub palette: Option<String>,
+    pub colours: Option<Vec<String>>,
+    pub keymap: KeymapConfig, // a preset replaces the keymap for all roms, keys go on top of it
+    pub gamepad: GamepadConfig, // on top of the controller settings for all roms
+    pub flicker: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeymapConfig {
+    pub preset: Option<String>,                // qwerty, azerty, dvorak or scancode
+    pub keys: BTreeMap<String, Vec<String>>, // hex key -> the keys bound to it
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GamepadConfig {
+    pub threshold: Option<f32>,              // how far a stick is pushed before it counts, 0.0 - 1.0
+    pub keys: BTreeMap<String, Vec<String>>, // hex key -> the buttons bound to it
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Config, String> {
+        let config: Config = toml::from_str(text).map_err(|e| e.to_string())?;
+        // The timers tick every speed instructions, so there has to be at least one
+        if let Some(key) = config.roms.iter().find(|(_, rom)| rom.speed == Some(0)).map(|(key, _)| key) {
+            return Err(format!("roms.{}: speed has to be at least 1", key));
+        }
+        Ok(config)
+    }
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Self::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+    // A rom's settings by the SHA-1 of its contents, or else by its file name
+    pub fn rom(&self, sha1: &str, name: Option<&str>) -> RomConfig {
+        let by_name = name.and_then(|name| self.roms.get(name));
+        self.roms.get(sha1).or(by_name).cloned().unwrap_or_default()
+    }
+    // $XDG_CONFIG_HOME/chip8/config.toml, or ~/.config/chip8/config.toml
+    pub fn default_path() -> Option<PathBuf> {
+        let dir = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+        };
+        Some(dir.join("chip8").join("config.toml"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_parse_config() {
+        let config = Config::parse("[display]\npalette = \"amber\"\n").unwrap();
+        assert_eq!(config.display.palette.as_deref(), Some("amber"));
+        assert!(config.display.colours.is_none());
+        assert!(Config::parse("").is_ok());
+        assert!(Config::parse("[display]\npallete = \"amber\"\n").is_err());
+        let config = Config::parse("[keymap]\nkeys = { 4 = [\"left\"], A = [\"scancode:57\"] }\n").unwrap();
+        assert_eq!(config.keymap.keys["4"], ["left"]);
+        assert_eq!(config.keymap.keys["A"], ["scancode:57"]);
+        let config = Config::parse("[roms.breakout]\nflicker = \"phosphor\"\n").unwrap();
+        assert_eq!(config.roms["breakout"].flicker.as_deref(), Some("phosphor"));
+    }
+    #[test]
+    fn test_rom_config() {
+        let text = "[roms.0123456789abcdef0123456789abcdef01234567]\nspeed = 20\nquirks = \"vip\"\n\
+                    keymap = { keys = { 4 = [\"left\"] } }\ngamepad = { keys = { 5 = [\"a\"] } }\n\n\
+                    [roms.pong]\nspeed = 12\n";
+        let config = Config::parse(text).unwrap();
+        let rom = config.rom("0123456789abcdef0123456789abcdef01234567", Some("pong"));
+        assert_eq!(rom.speed, Some(20));
+        assert_eq!(rom.quirks.as_deref(), Some("vip"));
+        assert_eq!(rom.keymap.keys["4"], ["left"]);
+        assert_eq!(rom.gamepad.keys["5"], ["a"]);
+        // The file name is the fallback, and unknown roms get no overrides
+        assert_eq!(config.rom("ffff", Some("pong")).speed, Some(12));
+        assert_eq!(config.rom("ffff", None).speed, None);
+        assert!(Config::parse("[roms.pong]\nsped = 12\n").is_err());
+        assert_eq!(Config::parse("[roms.pong]\nspeed = 0\n").unwrap_err(), "roms.pong: speed has to be at least 1");
+    }
+}
@@ -1,423 +1,721 @@
 use crate::fonts::FONTSET;
+use crate::framebuffer::Framebuffer;
 use crate::UPDATE_RATE;
-use array2d::{Array2D};
-use concat_arrays::concat_arrays;
+use recompiler::Block;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
 const START_PC: usize = 0x200;
 const OPCODE_SIZE: usize = 2; // one opcode is 16 bits, that is 2 bytes
+const RAM_SIZE: usize = 4096;
+const ADDRESS_MASK: u16 = 0x0FFF; // addresses are 12 bits, so the PC wraps around the RAM
+const STACK_SIZE: usize = 16;
+const DEFAULT_SEED: u64 = 0x2545F4914F6CDD1D;
+pub const DEFAULT_SPEED: u32 = (UPDATE_RATE / 60) as u32; // instructions per 60 Hz frame
 pub const CHIP_8_HEIGHT: usize = 32;
 pub const CHIP_8_WIDTH: usize = 64;
+// Where interpreters disagree, named like the flags of the community chip-8-database.
+// Each is the behaviour when the flag is set
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    pub shift: bool,                    // 8xy6/8xyE shift Vx in place instead of copying Vy first
+    pub logic: bool,                    // 8xy1/8xy2/8xy3 reset VF
+    pub memory_increment_by_x: bool,    // Fx55/Fx65 add X to I instead of X + 1
+    pub memory_leave_i_unchanged: bool, // Fx55/Fx65 don't touch I at all
+    pub wrap: bool,                     // sprites wrap around the edges instead of being clipped
+    pub jump: bool,                     // Bxnn jumps to xnn + Vx instead of nnn + V0
+    pub vblank: bool,                   // Dxyn waits for the next frame
+}
+
+// (name, shift, logic, memory_increment_by_x, memory_leave_i_unchanged, wrap, jump, vblank)
+const QUIRK_PROFILES: [(&str, [bool; 7]); 4] = [
+    // What this emulator always did, the default
+    ("chip-8", [false, true, false, true, false, false, false]),
+    ("vip", [false, true, false, false, false, false, true]),
+    ("schip", [true, false, false, true, false, true, false]),
+    ("xo-chip", [false, false, false, false, true, false, false]),
+];
+
+impl Quirks {
+    pub fn profile(name: &str) -> Result<Quirks, String> {
+        let (_, flags) = QUIRK_PROFILES
+            .iter()
+            .find(|(n, _)| *n == name)
+            .ok_or_else(|| format!("Unknown quirk profile: {}", name))?;
+        let [shift, logic, memory_increment_by_x, memory_leave_i_unchanged, wrap, jump, vblank] = *flags;
+        Ok(Quirks {
+            shift,
+            logic,
+            memory_increment_by_x,
+            memory_leave_i_unchanged,
+            wrap,
+            jump,
+            vblank,
+        })
+    }
+    // The profile these quirks match, if any
+    pub fn name(&self) -> Option<&'static str> {
+        QUIRK_PROFILES
+            .iter()
+            .map(|(name, _)| *name)
+            .find(|name| Quirks::profile(name).as_ref() == Ok(self))
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Self::profile("chip-8").unwrap()
+    }
+}
+
+// The machine a rom was written for. Only the CHIP-8 instructions are run,
+// the platform picks the quirks the rom expects
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Platform {
+    #[default]
+    Chip8,
+    Schip,
+    XoChip,
+}
+
+impl Platform {
+    pub fn name(self) -> &'static str {
+        match self {
+            Platform::Chip8 => "chip-8",
+            Platform::Schip => "schip",
+            Platform::XoChip => "xo-chip",
+        }
+    }
+    pub fn quirks(self) -> Quirks {
+        Quirks::profile(self.name()).unwrap()
+    }
+}
+
+impl FromStr for Platform {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "chip-8" => Ok(Platform::Chip8),
+            "schip" => Ok(Platform::Schip),
+            "xo-chip" => Ok(Platform::XoChip),
+            _ => Err(format!("Unknown platform: {}", s)),
+        }
+    }
+}
+
 #[derive(PartialEq, Copy, Clone)]
 enum KeyState {
     Up,
     Down,
 }
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct CpuError(String);
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl std::error::Error for CpuError {}
+// How instructions get from memory to running
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Engine {
+    #[default]
+    Interpreter, // decodes every instruction every time it runs
+    Cached, // decodes an instruction once and keeps it until its memory is written
+    Recompiler, // runs straight-line code as compiled blocks, see recompiler.rs
+}
+
+impl Engine {
+    pub fn name(self) -> &'static str {
+        match self {
+            Engine::Interpreter => "interpreter",
+            Engine::Cached => "cached",
+            Engine::Recompiler => "recompiler",
+        }
+    }
+}
+
+impl FromStr for Engine {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "interpreter" => Ok(Engine::Interpreter),
+            "cached" => Ok(Engine::Cached),
+            "recompiler" => Ok(Engine::Recompiler),
+            _ => Err(format!("Unknown engine: {}", s)),
+        }
+    }
+}
+
+type Handler = fn(&mut Cpu, &Decoded) -> Result<(), CpuError>;
+
+// An instruction taken apart: what runs it and its operands
+#[derive(Clone, Copy)]
+struct Decoded {
+    execute: Handler,
+    opcode: u16,
+    x: u8,
+    y: u8,
+    n: u8,
+    nn: u8,
+    nnn: u16,
+}
+
+impl Decoded {
+    fn x(&self) -> usize {
+        self.x as usize
+    }
+    fn y(&self) -> usize {
+        self.y as usize
+    }
+}
+
+// Straight on the heap, going through the stack would put the whole array in the frame of every cycle
+fn empty_cache() -> Box<[Option<Decoded>; RAM_SIZE]> {
+    match vec![None; RAM_SIZE].into_boxed_slice().try_into() {
+        Ok(cache) => cache,
+        Err(_) => unreachable!(),
+    }
+}
+
+fn decode(opcode: u16) -> Decoded {
+    let n = (opcode & 0x000F) as u8; // immediate - value hardcoded in the opcode
+    let nn = (opcode & 0x00FF) as u8;
+    let execute: Handler = match (opcode >> 12, n) {
+        (0x0, _) if nn == 0xE0 => Cpu::cls,
+        (0x0, _) if nn == 0xEE => Cpu::ret,
+        (0x1, _) => Cpu::jp,
+        (0x2, _) => Cpu::call,
+        (0x3, _) => Cpu::se_byte,
+        (0x4, _) => Cpu::sne_byte,
+        (0x5, _) => Cpu::se_reg,
+        (0x6, _) => Cpu::ld_byte,
+        (0x7, _) => Cpu::add_byte,
+        (0x8, 0x0) => Cpu::ld_reg,
+        (0x8, 0x1) => Cpu::or,
+        (0x8, 0x2) => Cpu::and,
+        (0x8, 0x3) => Cpu::xor,
+        (0x8, 0x4) => Cpu::add_reg,
+        (0x8, 0x5) => Cpu::sub,
+        (0x8, 0x6) => Cpu::shr,
+        (0x8, 0x7) => Cpu::subn,
+        (0x8, 0xE) => Cpu::shl,
+        (0x9, _) => Cpu::sne_reg,
+        (0xA, _) => Cpu::ld_i,
+        (0xB, _) => Cpu::jp_v0,
+        (0xC, _) => Cpu::rnd,
+        (0xD, _) => Cpu::drw,
+        (0xE, _) if nn == 0x9E => Cpu::skp,
+        (0xE, _) if nn == 0xA1 => Cpu::sknp,
+        (0xF, _) => match nn {
+            0x07 => Cpu::ld_vx_dt,
+            0x0A => Cpu::ld_vx_k,
+            0x15 => Cpu::ld_dt,
+            0x18 => Cpu::ld_st,
+            0x1E => Cpu::add_i,
+            0x29 => Cpu::ld_f,
+            0x33 => Cpu::ld_b,
+            0x55 => Cpu::store,
+            0x65 => Cpu::load,
+            _ => Cpu::unknown,
+        },
+        _ => Cpu::unknown,
+    };
+    Decoded {
+        execute,
+        opcode,
+        x: ((opcode >> 8) & 0x0F) as u8,
+        y: ((opcode >> 4) & 0x0F) as u8,
+        n,
+        nn,
+        nnn: opcode & 0x0FFF,
+    }
+}
+
 pub struct Cpu {
-    ram: [u8; 4096],        // Four KB of memory
+    ram: [u8; RAM_SIZE],        // Four KB of memory
     v: [u8; 16],                // 16 8-bit general purpose registers
     i: usize,                   // index register - used for stepping through arrays
-    stack: [u16; 16],           // stack - so far used for storing adresses
+    stack: [u16; STACK_SIZE],   // stack - so far used for storing adresses
     sp: usize,                  // stack pointer - points to the top of the stack
     pc: u16,     // program counter - stores the location of the instruction to be executed
     dt: u8,      // delay timer - decrements every cycle if not zero
     st: u8,      // sound timer - decrements every cycle if not zero
-    pub display: Array2D<bool>, // store screen pixels - black and white
-    timer_counter: u16, // counter to update dt in the desired intervals
+    display: Framebuffer, // store screen pixels - black and white
+    timer_counter: u32, // counter to update dt in the desired intervals
     keys: [KeyState; 16],
     halt: bool, // Field for the instruction Fx0A
     halt_idx: usize, // Field for the instruction Fx0A
     pub beep: bool, 
+    frames: u64, // number of times the timers ticked, i.e. 60 Hz frames
+    display_wait: bool, // waiting for the next frame after a draw, with the vblank quirk
+    rng: u64,           // xorshift state for Cxkk
+    last_instruction: Option<(u16, u16)>, // address and opcode run by the last cycle
+    cache: Option<Box<[Option<Decoded>; RAM_SIZE]>>, // decoded instructions by address, once an engine uses it
+    blocks: Vec<Option<Option<Arc<Block>>>>, // by address once compiled, empty until the recompiler runs
+    in_blocks: Vec<bool>, // the bytes compiled blocks were made from, by address
+    last_block: Option<Arc<Block>>,          // the block the last call to emulate_cycles ran
+    // Settings rather than state, a reset keeps them
+    pub platform: Platform,
+    pub quirks: Quirks,
+    pub speed: u32, // instructions per frame
+    pub engine: Engine,
+    seed: u64,
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Cpu {
     pub fn new() -> Self {
+        let mut ram = [0; RAM_SIZE];
+        ram[..FONTSET.len()].copy_from_slice(&FONTSET); // add fonts, the rest is 0's
         Cpu {
-            ram: concat_arrays!(FONTSET, [0; 4016]), // add fonts, the rest is 0's
+            ram,
             v: [0; 16],                              // fill all registers with 0's
             i: 0,                                    // set index register to 0's
-            stack: [0; 16],                          // fill the stack with 0's
+            stack: [0; STACK_SIZE],                  // fill the stack with 0's
             sp: 0,
             pc: START_PC as u16, // program counter starts at 0x200 (earlier is the interpreter code)
             dt: 0,               // not sure about this one
             st: 0,               // not sure about this one
-            display: Array2D::filled_with(false, CHIP_8_WIDTH, CHIP_8_HEIGHT), // set all pixels to black
+            display: Framebuffer::default(), // set all pixels to black
             timer_counter: 0,
             keys: [KeyState::Up; 16],
             halt: false, 
             halt_idx:  0, 
             beep: false, 
+            frames: 0,
+            display_wait: false,
+            rng: DEFAULT_SEED,
+            last_instruction: None,
+            cache: None,
+            blocks: vec![],
+            in_blocks: vec![],
+            last_block: None,
+            platform: Platform::default(),
+            quirks: Quirks::default(),
+            speed: DEFAULT_SPEED,
+            engine: Engine::default(),
+            seed: DEFAULT_SEED,
         }
     }
+    // Decode and execute one opcode, the way the interpreter engine runs every instruction
     fn run_opcode(&mut self, opcode: u16) -> Result<(), CpuError> {
-        // Decode and Execute opcode
-        let x: usize = ((opcode >> 8) & 0x0F).try_into().unwrap(); // index, so we cast
-        let y: usize = ((opcode >> 4) & 0x00F).try_into().unwrap(); // index, so we cast
-        let n: u8 = (opcode & 0x000F).try_into().unwrap(); // immediate - value hardcoded in the opcode
-        let nn: u8 = (opcode & 0x00FF).try_into().unwrap();
-        let nnn: u16 = opcode & 0x0FFF;
-        let f: u16 = opcode & 0xF000; // The first nibble
-        let vxlow: usize = (self.v[x] & 0x0F) as usize; // the lowest nibble of self.v[x]
-
-        // Increment the PC after fetching the opcode and before decoding
+        self.execute(&decode(opcode))
+    }
+    fn execute(&mut self, decoded: &Decoded) -> Result<(), CpuError> {
+        // Increment the PC after fetching the opcode and before executing
         self.increment_pc();
-
-        match f {
-            0xC000 => {
-             // Cxkk - RND Vx, byte
-             let random: u8 = 10;
-             self.v[x] = nn & random;
-             Ok(())
-            }
-            0xB000 => {
-                // Bnnn - JP V0, addr
-                self.pc = self.v[0x0] as u16 + nnn;
-                Ok(())
-            }
-            0x1000 => {
-                // 1nnn - JP addr
-                self.pc = nnn;
-                Ok(())
-            }
-            0x6000 => {
-                // 6xnn - LD Vx, byte
-                self.v[x] = nn;
-                Ok(())
-            }
-            0x7000 => {
-                // 7xnn - ADD Vx, byte
-                self.v[x] = self.v[x].overflowing_add(nn).0;
-                Ok(())
-            }
-            0xA000 => {
-                // Annn - LD I, addr
-                self.i = nnn.try_into().unwrap();
-                Ok(())
-            }
-            0xD000 => {
-                // Dxyn - DRW Vx, Vy, nibble
-                // Ensure V[x], V[y] are in bounds
-                let vx: usize = self.v[x] as usize % CHIP_8_WIDTH;
-                let vy: usize = self.v[y] as usize % CHIP_8_HEIGHT;
-                // Set VF to 0
-                self.v[0xF] = 0;
-                // the bounds of a range must have matching types
-                for r in 0_usize..n.try_into().unwrap() {
-                    // Find y index
-                    let idx_y: usize = vy + r;
-                    // If idx_y out of bounds, discard it
-                    if idx_y >= CHIP_8_HEIGHT {
-                        break;
-                    }
-                    let curr_byte: u8 = self.ram[self.i + r];
-                    for z in 0..8_usize {
-                        // Find x index
-                        let idx_x: usize = vx + z;
-                        // If idx_x out of bounds, discard it
-                        if idx_x >= CHIP_8_WIDTH {
-                            break;
-                        }
-                        if curr_byte & 1 << (7 - z) == 0 {
-                            continue;
-                        }
-                        if self.display[(idx_x, idx_y)] == true {
-                            self.v[0xF] = 1;
-                        }
-                        self.display[(idx_x, idx_y)] ^= true;
-                    }
-                }
-                Ok(())
-            }
-            0x3000 => {
-                // 3xkk - SE Vx, byte
-                if self.v[x] == nn {
-                    self.increment_pc();
-                }
-                Ok(())
-            }
-            0x4000 => {
-                // 4xkk - SNE Vx, byte
-                if self.v[x] != nn {
-                    self.increment_pc();
-                }
-                Ok(())
-            }
-
-            0x8000 => {
-                match n {
-                    // 8xy4 - ADD Vx, Vy
-                    0x4 => {
-                        let (result, overflow) = self.v[x].overflowing_add(self.v[y]);
-                        self.v[x] = result;
-                        if overflow {
-                            self.v[0xF] = 1;
-                        } else {
-                            self.v[0xF] = 0;
-                        }
-                        Ok(())
-                    }
-                    0x5 => {
-                        // 8xy5 - SUB Vx, Vy
-                        // What kind of subtraction are we after? I think overflowing
-                        let (result, underflow) = self.v[x].overflowing_sub(self.v[y]);
-                        self.v[x] = result;
-                        if underflow {
-                            // Cowgod says it should be >
-                            self.v[0xF] = 0;
-                        } else {
-                            self.v[0xF] = 1;
-                        }
-                        Ok(())
-                    }
-                    0x0 => {
-                        // 8xy0 - LD Vx, Vy
-                        self.v[x] = self.v[y];
-                        Ok(())
-                    }
-                    0x7 => {
-                        // 8xy7 - SUB Vx, Vy
-                        // What kind of subtraction are we after? I think overflowing
-                        let (result, underflow) = self.v[y].overflowing_sub(self.v[x]);
-                        self.v[x] = result;
-                        if underflow {
-                            self.v[0xF] = 0;
-                        } else {
-                            self.v[0xF] = 1;
-                        }
-                        Ok(())
-                    }
-                    0x1 => {
-                        // 8xy1 - OR Vx, Vy
-                        self.v[x] = self.v[x] | self.v[y];
-                        // Quirk
-                        self.v[0xF] = 0;
-                        Ok(())
-                    }
-                    0x6 => {
-                        //  8xy6 - SHR Vx {, Vy}
-                        // TODO: make this configurable
-                        if true {
-                            self.v[x] = self.v[y];
-                        }
-                        let mut temp = 0;
-                        if self.v[x] & 1 == 1 {
-                            temp = 1;
-                        }
-                        self.v[x] >>= 1;
-                        self.v[0xF] = temp;
-                        Ok(())
-                    }
-                    0x2 => {
-                        // 8xy2 - AND Vx, Vy
-                        self.v[x] = self.v[x] & self.v[y];
-                        // Quirk
-                        self.v[0xF] = 0; 
-                        Ok(())
-                    }
-                    0xE => {
-                        // 8xyE - SHL Vx {, Vy}
-                        // TODO: make this configurable
-                        if true {
-                            self.v[x] = self.v[y];
-                        }
-                        let mut temp = 0;
-                        if self.v[x] >> 7 & 1 == 1 {
-                            temp = 1;
-                        }
-                        self.v[x] <<= 1;
-                        self.v[0xF] = temp;
-                        Ok(())
-                    }
-                    0x3 => {
-                        // 8xy3 - XOR Vx, Vy
-                        self.v[x] ^= self.v[y];
-                        // Quirk
-                        self.v[0xF] = 0; 
-                        Ok(())
-                    }
-
-                    _ => {
-                        // Return error
-                        Err(CpuError(format!("Unknown OpCode: {:#06X}", opcode)))
-                    }
-                }
-            }
-            0xF000 => {
-                match nn {
-                    // Fx29 - LD F, Vx
-                    0x29 => {
-                        self.i = self.v[x] as usize * 5;
-                        Ok(())
-                    }
-                    // Fx0A - LD Vx, K
-                    0x0A => {
-                        self.halt_idx = x;
-                        self.halt = true;
-                        Ok(())
-                    }
-                    // Fx07 - LD Vx, DT
-                    0x07 => {
-                        self.v[x] = self.dt;
-                        Ok(())
-                    }
-                    // Fx15 - LD DT, Vx
-                    0x15 => {
-                        self.dt = self.v[x];
-                        Ok(())
-                    }
-                    // Fx65 - LD Vx, [I]
-                    0x65 => {
-                        for idx in 0..x + 1 {
-                            self.v[idx] = self.ram[self.i + idx];
-                        }
-                        Ok(())
-                    }
-                    // Fx55 - LD [I], Vx
-                    0x55 => {
-                        // TODO: make an option for configuring the behaviour
-                        for idx in 0..x + 1 {
-                            self.ram[self.i + idx] = self.v[idx];
-                        }
-                        Ok(())
-                    }
-                    // Fx33 - LD B, Vx
-                    0x33 => {
-                        let hundreds: u8 = self.v[x] / 100;
-                        let tens: u8 = (self.v[x] % 100) / 10;
-                        let ones: u8 = self.v[x] % 10;
-                        self.ram[self.i] = hundreds;
-                        self.ram[self.i + 1] = tens;
-                        self.ram[self.i + 2] = ones;
-                        Ok(())
-                    }
-                    0x1E => {
-                        // Fx1E - ADD I, Vx
-                        self.i = self.i.saturating_add(self.v[x].into());
-                        Ok(())
-                    }
-                    0x18 => {
-                        // Fx18 - LD ST, Vx
-                        self.st = self.v[x];
-                        Ok(())
-                    }
-                    _ => {
-                        // Return error
-                        Err(CpuError(format!("Unknown OpCode: {:#06X}", opcode)))
-                    }
-                }
-            }
-            0x2000 => {
-                // 2nnn - CALL addr
-                self.stack[self.sp] = self.pc;
-                self.sp += 1;
-                self.pc = nnn;
-                Ok(())
-            }
-
-            0x0000 => {
-                //Handle CLS and RET
-                match nn {
-                    0xE0 => {
-                        //CLS
-                        self.display = Array2D::filled_with(false, CHIP_8_WIDTH, CHIP_8_HEIGHT); // set all pixels to black
-                        Ok(())
-                    }
-                    0xEE => {
-                        //RET
-                        // Subtract from the sp
-                        self.sp -= 1;
-                        // set the pc to the address at the top of the stack
-                        self.pc = self.stack[self.sp];
-                        Ok(())
-                    }
-                    _ => {
-                        // Return error
-                        Err(CpuError(format!("Unknown OpCode: {:#06X}", opcode)))
+        (decoded.execute)(self, decoded)
+    }
+    // The decoded instruction at pc, decoded now if it isn't in the cache
+    fn cached(&mut self, pc: usize) -> Decoded {
+        if let Some(Some(decoded)) = self.cache.as_ref().map(|cache| cache[pc]) {
+            return decoded;
+        }
+        let decoded = decode(self.fetch(pc));
+        // Only CPUs running the cached engines pay for the cache
+        self.cache.get_or_insert_with(empty_cache)[pc] = Some(decoded);
+        decoded
+    }
+    // Forget the decoded instructions that read any of len bytes from start, after they were written
+    fn invalidate(&mut self, start: usize, len: usize) {
+        // The instruction starting the byte before reads the first byte too
+        if let Some(cache) = &mut self.cache {
+            cache[start.saturating_sub(1)..start + len].fill(None);
+        }
+        // Writes to data rather than code are the usual case, and leave the blocks alone
+        if !self.blocks.is_empty() && self.in_blocks[start..start + len].contains(&true) {
+            // So may a block starting up to a block's length before, but only the ones that
+            // reach the written bytes have to go. Where there's no block the interpreter runs
+            // whatever is there, so that can stay as it is
+            let first = start.saturating_sub(recompiler::MAX_BLOCK * OPCODE_SIZE);
+            for address in first..start + len {
+                if let Some(Some(block)) = &self.blocks[address] {
+                    if address + block.instructions.len() * OPCODE_SIZE > start {
+                        self.blocks[address] = None;
                     }
                 }
             }
-            0x5000 => {
-                if self.v[x] == self.v[y] {
-                    self.increment_pc();
-                }
-                Ok(())
-            }
-            0x9000 => {
-                if self.v[x] != self.v[y] {
-                    self.increment_pc();
-                }
-                Ok(())
-            }
-            0xE000 => match nn {
-                0x9E => {
-                    // Ex9E - SKP Vx
-                    if self.keys[vxlow] == KeyState::Down {
-                        self.increment_pc();
-                    }
-                    Ok(())
-                }
-                0xA1 => {
-                    // ExA1 - SKNP Vx
-                    if self.keys[vxlow] == KeyState::Up {
-                        self.increment_pc();
-                    }
-                    Ok(())
-                }
-                _ => Err(CpuError(format!("Unknown OpCode: {:#06X}", opcode))),
-            },
-            _ => Err(CpuError(format!("Unknown OpCode: {:#06X}", opcode))),
-        }
-    }
-    fn translate(key: i32) -> Option<u8> {
-        // Translate the scancodes to 0-F values
-        match key {
-            2 => Some(0x1),
-            3 => Some(0x2),
-            4 => Some(0x3),
-            5 => Some(0xC),
-            16 => Some(0x4),
-            17 => Some(0x5),
-            18 => Some(0x6),
-            19 => Some(0xD),
-            30 => Some(0x7),
-            31 => Some(0x8),
-            32 => Some(0x9),
-            33 => Some(0xE),
-            44 => Some(0xA),
-            45 => Some(0x0),
-            46 => Some(0xB),
-            47 => Some(0xF),
-            _ => None,
         }
     }
-
-    pub fn key_pressed(&mut self, key: i32) {
-        if let Some(k) = Self::translate(key) {
-            self.keys[k as usize] = KeyState::Down;
-            // If we're halting (Fx0A has been called),
-            // save the key value to self.v[x] negate self.halt
-            if self.halt {
-                self.v[self.halt_idx] = k;
-            }
+    fn unknown(&mut self, d: &Decoded) -> Result<(), CpuError> {
+        Err(CpuError(format!("Unknown OpCode: {:#06X}", d.opcode)))
+    }
+    fn cls(&mut self, _: &Decoded) -> Result<(), CpuError> {
+        // 00E0 - CLS
+        self.display.clear(); // set all pixels to black
+        Ok(())
+    }
+    fn ret(&mut self, d: &Decoded) -> Result<(), CpuError> {
+        // 00EE - RET
+        if self.sp == 0 {
+            return Err(CpuError(format!("Stack underflow at {:#06X}: {:#06X}", self.pc, d.opcode)));
         }
+        // Subtract from the sp
+        self.sp -= 1;
+        // set the pc to the address at the top of the stack
+        self.pc = self.stack[self.sp];
+        Ok(())
     }
-    pub fn key_released(&mut self, key: i32) {
-        if let Some(k) = Self::translate(key) {
-            self.keys[k as usize] = KeyState::Up;
-            // When pressed key is released, stop halting
-            if self.halt {
-                self.halt = false;
-            }
+    fn jp(&mut self, d: &Decoded) -> Result<(), CpuError> {
+        // 1nnn - JP addr
+        self.pc = d.nnn;
+        Ok(())
+    }
+    fn call(&mut self, d: &Decoded) -> Result<(), CpuError> {
+        // 2nnn - CALL addr
+        if self.sp >= STACK_SIZE {
+            return Err(CpuError(format!("Stack overflow at {:#06X}: {:#06X}", self.pc, d.opcode)));
         }
+        self.stack[self.sp] = self.pc;
+        self.sp += 1;
+        self.pc = d.nnn;
+        Ok(())
+    }
+    fn se_byte(&mut self, d: &Decoded) -> Result<(), CpuError> {
+        // 3xkk - SE Vx, byte
+        if self.v[d.x()] == d.nn {
+            self.increment_pc();
+        }
+        Ok(())
+    }
+    fn sne_byte(&mut self, d: &Decoded) -> Result<(), CpuError> {
+        // 4xkk - SNE Vx, byte
+        if self.v[d.x()] != d.nn {
+            self.increment_pc();
+        }
+        Ok(())
+    }
+    fn se_reg(&mut self, d: &Decoded) -> Result<(), CpuError> {
+        // 5xy0 - SE Vx, Vy
+        if self.v[d.x()] == self.v[d.y()] {
+            self.increment_pc();
+        }
+        Ok(())
+    }
+    fn ld_byte(&mut self, d: &Decoded) -> Result<(), CpuError> {
+        // 6xnn - LD Vx, byte
+        self.v[d.x()] = d.nn;
+        Ok(())
+    }
+    fn add_byte(&mut self, d: &Decoded) -> Result<(), CpuError> {
+        // 7xnn - ADD Vx, byte
+        self.v[d.x()] = self.v[d.x()].overflowing_add(d.nn).0;
+        Ok(())
+    }
+    fn ld_reg(&mut self, d: &Decoded) -> Result<(), CpuError> {
+        // 8xy0 - LD Vx, Vy
+        self.v[d.x()] = self.v[d.y()];
+        Ok(())
+    }
+    fn or(&mut self, d: &Decoded) -> Result<(), CpuError> {
+        // 8xy1 - OR Vx, Vy
+        self.v[d.x()] |= self.v[d.y()];
+        if self.quirks.logic {
+            self.v[0xF] = 0;
+        }
+        Ok(())
+    }
+    fn and(&mut self, d: &Decoded) -> Result<(), CpuError> {
+        // 8xy2 - AND Vx, Vy
+        self.v[d.x()] &= self.v[d.y()];
+        if self.quirks.logic {
+            self.v[0xF] = 0;
+        }
+        Ok(())
+    }
+    fn xor(&mut self, d: &Decoded) -> Result<(), CpuError> {
+        // 8xy3 - XOR Vx, Vy
+        self.v[d.x()] ^= self.v[d.y()];
+        if self.quirks.logic {
+            self.v[0xF] = 0;
+        }
+        Ok(())
+    }
+    fn add_reg(&mut self, d: &Decoded) -> Result<(), CpuError> {
+        // 8xy4 - ADD Vx, Vy
+        let (result, overflow) = self.v[d.x()].overflowing_add(self.v[d.y()]);
+        self.v[d.x()] = result;
+        if overflow {
+            self.v[0xF] = 1;
+        } else {
+            self.v[0xF] = 0;
+        }
+        Ok(())
+    }
+    fn sub(&mut self, d: &Decoded) -> Result<(), CpuError> {
+        // 8xy5 - SUB Vx, Vy
+        // What kind of subtraction are we after? I think overflowing
+        let (result, underflow) = self.v[d.x()].overflowing_sub(self.v[d.y()]);
+        self.v[d.x()] = result;
+        if underflow {
+            // Cowgod says it should be >
+            self.v[0xF] = 0;
+        } else {
+            self.v[0xF] = 1;
+        }
+        Ok(())
+    }
+    fn shr(&mut self, d: &Decoded) -> Result<(), CpuError> {
+        //  8xy6 - SHR Vx {, Vy}
+        if !self.quirks.shift {
+            self.v[d.x()] = self.v[d.y()];
+        }
+        let mut temp = 0;
+        if self.v[d.x()] & 1 == 1 {
+            temp = 1;
+        }
+        self.v[d.x()] >>= 1;
+        self.v[0xF] = temp;
+        Ok(())
+    }
+    fn subn(&mut self, d: &Decoded) -> Result<(), CpuError> {
+        // 8xy7 - SUBN Vx, Vy
+        // What kind of subtraction are we after? I think overflowing
+        let (result, underflow) = self.v[d.y()].overflowing_sub(self.v[d.x()]);
+        self.v[d.x()] = result;
+        if underflow {
+            self.v[0xF] = 0;
+        } else {
+            self.v[0xF] = 1;
+        }
+        Ok(())
+    }
+    fn shl(&mut self, d: &Decoded) -> Result<(), CpuError> {
+        // 8xyE - SHL Vx {, Vy}
+        if !self.quirks.shift {
+            self.v[d.x()] = self.v[d.y()];
+        }
+        let mut temp = 0;
+        if self.v[d.x()] >> 7 & 1 == 1 {
+            temp = 1;
+        }
+        self.v[d.x()] <<= 1;
+        self.v[0xF] = temp;
+        Ok(())
+    }
+    fn sne_reg(&mut self, d: &Decoded) -> Result<(), CpuError> {
+        // 9xy0 - SNE Vx, Vy
+        if self.v[d.x()] != self.v[d.y()] {
+            self.increment_pc();
+        }
+        Ok(())
+    }
+    fn ld_i(&mut self, d: &Decoded) -> Result<(), CpuError> {
+        // Annn - LD I, addr
+        self.i = d.nnn.into();
+        Ok(())
+    }
+    fn jp_v0(&mut self, d: &Decoded) -> Result<(), CpuError> {
+        // Bnnn - JP V0, addr
+        let offset = if self.quirks.jump { self.v[d.x()] } else { self.v[0x0] };
+        self.pc = (offset as u16 + d.nnn) & ADDRESS_MASK;
+        Ok(())
+    }
+    fn rnd(&mut self, d: &Decoded) -> Result<(), CpuError> {
+        // Cxkk - RND Vx, byte
+        let random = self.random();
+        self.v[d.x()] = d.nn & random;
+        Ok(())
+    }
+    fn drw(&mut self, d: &Decoded) -> Result<(), CpuError> {
+        // Dxyn - DRW Vx, Vy, nibble
+        // Ensure V[x], V[y] are in bounds
+        let vx: usize = self.v[d.x()] as usize % self.display.width();
+        let vy: usize = self.v[d.y()] as usize % self.display.height();
+        self.check_ram_access(self.i, d.n.into(), d.opcode)?;
+        let sprite = &self.ram[self.i..self.i + d.n as usize];
+        // VF is set if a lit pixel was turned off
+        let collision = self.display.draw_sprite(vx, vy, sprite, self.quirks.wrap);
+        self.v[0xF] = collision as u8;
+        self.display_wait = self.quirks.vblank;
+        Ok(())
+    }
+    fn skp(&mut self, d: &Decoded) -> Result<(), CpuError> {
+        // Ex9E - SKP Vx
+        if self.keys[(self.v[d.x()] & 0x0F) as usize] == KeyState::Down {
+            self.increment_pc();
+        }
+        Ok(())
+    }
+    fn sknp(&mut self, d: &Decoded) -> Result<(), CpuError> {
+        // ExA1 - SKNP Vx
+        if self.keys[(self.v[d.x()] & 0x0F) as usize] == KeyState::Up {
+            self.increment_pc();
+        }
+        Ok(())
+    }
+    fn ld_vx_dt(&mut self, d: &Decoded) -> Result<(), CpuError> {
+        // Fx07 - LD Vx, DT
+        self.v[d.x()] = self.dt;
+        Ok(())
+    }
+    fn ld_vx_k(&mut self, d: &Decoded) -> Result<(), CpuError> {
+        // Fx0A - LD Vx, K
+        self.halt_idx = d.x();
+        self.halt = true;
+        Ok(())
+    }
+    fn ld_dt(&mut self, d: &Decoded) -> Result<(), CpuError> {
+        // Fx15 - LD DT, Vx
+        self.dt = self.v[d.x()];
+        Ok(())
+    }
+    fn ld_st(&mut self, d: &Decoded) -> Result<(), CpuError> {
+        // Fx18 - LD ST, Vx
+        self.st = self.v[d.x()];
+        Ok(())
+    }
+    fn add_i(&mut self, d: &Decoded) -> Result<(), CpuError> {
+        // Fx1E - ADD I, Vx
+        self.i = self.i.saturating_add(self.v[d.x()].into());
+        Ok(())
+    }
+    fn ld_f(&mut self, d: &Decoded) -> Result<(), CpuError> {
+        // Fx29 - LD F, Vx
+        self.i = self.v[d.x()] as usize * 5;
+        Ok(())
+    }
+    fn ld_b(&mut self, d: &Decoded) -> Result<(), CpuError> {
+        // Fx33 - LD B, Vx
+        self.check_ram_access(self.i, 3, d.opcode)?;
+        let vx = self.v[d.x()];
+        let hundreds: u8 = vx / 100;
+        let tens: u8 = (vx % 100) / 10;
+        let ones: u8 = vx % 10;
+        self.ram[self.i] = hundreds;
+        self.ram[self.i + 1] = tens;
+        self.ram[self.i + 2] = ones;
+        self.invalidate(self.i, 3);
+        Ok(())
+    }
+    fn store(&mut self, d: &Decoded) -> Result<(), CpuError> {
+        // Fx55 - LD [I], Vx
+        let x = d.x();
+        self.check_ram_access(self.i, x + 1, d.opcode)?;
+        for idx in 0..x + 1 {
+            self.ram[self.i + idx] = self.v[idx];
+        }
+        self.invalidate(self.i, x + 1);
+        self.advance_i(x);
+        Ok(())
+    }
+    fn load(&mut self, d: &Decoded) -> Result<(), CpuError> {
+        // Fx65 - LD Vx, [I]
+        let x = d.x();
+        self.check_ram_access(self.i, x + 1, d.opcode)?;
+        for idx in 0..x + 1 {
+            self.v[idx] = self.ram[self.i + idx];
+        }
+        self.advance_i(x);
+        Ok(())
+    }
+    // Whether a key on the hex keypad (0-F) is held down
+    pub fn key_down(&self, k: u8) -> bool {
+        k <= 0xF && self.keys[k as usize] == KeyState::Down
+    }
+    // Press a key on the hex keypad (0-F)
+    pub fn press_key(&mut self, k: u8) {
+        if k > 0xF {
+            return;
+        }
+        self.keys[k as usize] = KeyState::Down;
+        // If we're halting (Fx0A has been called),
+        // save the key value to self.v[x] negate self.halt
+        if self.halt {
+            self.v[self.halt_idx] = k;
+        }
+    }
+    // Release a key on the hex keypad (0-F)
+    pub fn release_key(&mut self, k: u8) {
+        if k > 0xF {
+            return;
+        }
+        self.keys[k as usize] = KeyState::Up;
+        // When pressed key is released, stop halting
+        if self.halt {
+            self.halt = false;
+        }
+    }
+    // Where I ends up after Fx55/Fx65 store or load V0..Vx
+    fn advance_i(&mut self, x: usize) {
+        if self.quirks.memory_increment_by_x {
+            self.i += x;
+        } else if !self.quirks.memory_leave_i_unchanged {
+            self.i += x + 1;
+        }
+    }
+    // xorshift64*, good enough for games and the same on every machine for a seed
+    fn random(&mut self) -> u8 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        (self.rng.wrapping_mul(0x2545F4914F6CDD1D) >> 56) as u8
+    }
+    // Where Cxkk's random numbers start, a reset starts them over
+    pub fn seed(&mut self, seed: u64) {
+        self.seed = seed;
+        // xorshift gets stuck on 0
+        self.rng = if seed == 0 { DEFAULT_SEED } else { seed };
     }
     fn increment_pc(&mut self) {
         // Increments the PC
-        self.pc += OPCODE_SIZE as u16;
+        self.pc = (self.pc + OPCODE_SIZE as u16) & ADDRESS_MASK;
+    }
+    fn check_ram_access(&self, start: usize, len: usize, opcode: u16) -> Result<(), CpuError> {
+        // Make sure I..I+len lies inside the RAM before touching it
+        if start > RAM_SIZE || len > RAM_SIZE - start {
+            return Err(CpuError(format!(
+                "Memory access out of bounds: I = {:#06X}, {:#06X}",
+                start, opcode
+            )));
+        }
+        Ok(())
+    }
+    // One cycle, or with the recompiler a whole block of them when one starts at the PC and
+    // ends before the timers next tick. How many cycles were run
+    pub fn emulate_cycles(&mut self) -> Result<u32, CpuError> {
+        if self.engine == Engine::Recompiler && !self.halt && !self.display_wait {
+            if let Some(block) = self.block(self.pc as usize) {
+                let cycles = block.instructions.len() as u32;
+                if self.timer_counter + cycles < self.speed {
+                    self.timer_counter += cycles;
+                    block.run(self);
+                    self.last_instruction = block.instructions.last().copied();
+                    self.last_block = Some(block);
+                    return Ok(cycles);
+                }
+            }
+        }
+        self.emulate_cycle().map(|()| 1)
+    }
+    // The block starting at pc, compiled now if it hasn't been yet
+    fn block(&mut self, pc: usize) -> Option<Arc<Block>> {
+        if self.blocks.is_empty() {
+            // Only CPUs running the recompiler pay for the blocks
+            self.blocks = vec![None; RAM_SIZE];
+            self.in_blocks = vec![false; RAM_SIZE];
+        }
+        if self.blocks[pc].is_none() {
+            let block = recompiler::compile(&self.ram, pc).map(Arc::new);
+            if let Some(block) = &block {
+                self.in_blocks[pc..pc + block.instructions.len() * OPCODE_SIZE].fill(true);
+            }
+            self.blocks[pc] = Some(block);
+        }
+        self.blocks[pc].clone().flatten()
     }
     pub fn emulate_cycle(&mut self) -> Result<(), CpuError> {
+        self.last_instruction = None;
+        self.last_block = None;
         // update timers 60 times per second
         self.timer_counter += 1;
-        if self.timer_counter as u64 >= UPDATE_RATE / 60 {
+        if self.timer_counter >= self.speed {
             if self.dt > 0 {
                 self.dt -= 1;
             }
@@ -427,27 +725,127 @@ impl Cpu {
                 self.st -= 1;
             }
             self.timer_counter = 0;
+            self.frames += 1;
+            self.display_wait = false;
         }
         // while self.st > 0 we should beep
         // For the purpose of the Fx0A instruction
         // all execution stops, but the timers are still ticking down
-        if self.halt {
+        if self.halt || self.display_wait {
             return Ok(());
         }
         // Here is implemented the Fetch-Decode-Execute cycle
         // allows pc to be used as an index
         let pc: usize = self.pc as usize;
-        // Fetch opcode
+        if pc + 1 >= RAM_SIZE {
+            return Err(CpuError(format!("PC out of bounds: {:#06X}", pc)));
+        }
+        match self.engine {
+            Engine::Interpreter => {
+                let opcode = self.fetch(pc);
+                self.last_instruction = Some((self.pc, opcode));
+                self.run_opcode(opcode)
+            }
+            // The recompiler leaves everything but blocks to the cached interpreter
+            Engine::Cached | Engine::Recompiler => {
+                let decoded = self.cached(pc);
+                self.last_instruction = Some((self.pc, decoded.opcode));
+                self.execute(&decoded)
+            }
+        }
+    }
+    fn fetch(&self, pc: usize) -> u16 {
         let upper: u16 = self.ram[pc].into();
         let lower: u16 = self.ram[pc + 1].into();
-        let opcode: u16 = (upper << 8) | lower; // merge two bytes
-        self.run_opcode(opcode)?;
-        Ok(())
+        (upper << 8) | lower // merge two bytes
+    }
+
+    // The address and opcode of the instruction the last cycle ran, if it ran one
+    pub fn last_instruction(&self) -> Option<(u16, u16)> {
+        self.last_instruction
+    }
+    // All the instructions the last call to emulate_cycle or emulate_cycles ran, in order
+    pub fn last_instructions(&self) -> &[(u16, u16)] {
+        match &self.last_block {
+            Some(block) => &block.instructions,
+            None => self.last_instruction.as_slice(),
+        }
+    }
+    // The screen, read-only: only instructions draw on it
+    pub fn display(&self) -> &Framebuffer {
+        &self.display
+    }
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+    pub fn v(&self) -> [u8; 16] {
+        self.v
+    }
+    pub fn i(&self) -> usize {
+        self.i
+    }
+    pub fn sp(&self) -> usize {
+        self.sp
+    }
+    pub fn dt(&self) -> u8 {
+        self.dt
+    }
+    pub fn st(&self) -> u8 {
+        self.st
+    }
+    // Carry on from somewhere else, e.g. past an instruction that failed
+    pub fn jump(&mut self, address: u16) {
+        self.pc = address & ADDRESS_MASK;
+        self.halt = false;
+        self.display_wait = false;
+    }
+    // Number of 60 Hz frames emulated so far
+    pub fn frames(&self) -> u64 {
+        self.frames
     }
 
-    pub fn load_rom(&mut self, rom: Vec<u8>) {
-        for i in 0..rom.len() {
-            self.ram[0x200 + i] = rom[i];
+    // Back to the power-on state, in place. The rom is wiped with the rest of
+    // the memory, so it has to be loaded again
+    pub fn reset(&mut self) {
+        self.ram[FONTSET.len()..].fill(0);
+        self.ram[..FONTSET.len()].copy_from_slice(&FONTSET);
+        self.v = [0; 16];
+        self.i = 0;
+        self.stack = [0; STACK_SIZE];
+        self.sp = 0;
+        self.pc = START_PC as u16;
+        self.dt = 0;
+        self.st = 0;
+        self.display.clear();
+        self.timer_counter = 0;
+        self.keys = [KeyState::Up; 16];
+        self.halt = false;
+        self.halt_idx = 0;
+        self.beep = false;
+        self.frames = 0;
+        self.display_wait = false;
+        self.seed(self.seed);
+        self.last_instruction = None;
+        if let Some(cache) = &mut self.cache {
+            cache.fill(None);
         }
+        self.blocks.fill(None);
+        self.in_blocks.fill(false);
+        self.last_block = None;
+    }
+    pub fn load_rom(&mut self, rom: Vec<u8>) -> Result<(), CpuError> {
+        if rom.len() > RAM_SIZE - START_PC {
+            return Err(CpuError(format!("Rom too big: {} bytes", rom.len())));
+        }
+        self.ram[START_PC..START_PC + rom.len()].copy_from_slice(&rom);
+        self.invalidate(START_PC, rom.len());
+        Ok(())
     }
 }
+
+#[path = "recompiler.rs"]
+mod recompiler;
+
+#[cfg(test)]
+#[path = "tests.rs"]
+mod tests;
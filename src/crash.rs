@@ -0,0 +1,110 @@
+// What the machine looked like when an instruction failed
+//
+// The driver takes the report and pauses, the window and the terminal show
+// it over the game and headless runs print it:
+//
+//     Unknown OpCode: 0x0000
+//     PC 0x202  Opcode 0000  I 0x000  SP 0  DT 0  ST 0
+//     V0 00 V1 00 V2 00 V3 00 V4 00 V5 00 V6 00 V7 00
+//     V8 00 V9 00 VA 00 VB 00 VC 00 VD 00 VE 00 VF 00
+//     Last instructions:
+//       0x200  00E0  CLS
+//       0x202  0000  DW 0x0000
+use crate::cpu::{Cpu, CpuError};
+use crate::disasm::disassemble;
+use std::fmt;
+
+// How many instructions the report goes back
+pub const HISTORY_LENGTH: usize = 5;
+// The keys the frontends take while the report is up
+pub const CRASH_KEYS: &str = "R reset  C continue  Q quit";
+
+#[derive(Debug, Clone)]
+pub struct Crash {
+    pub error: CpuError,
+    pub pc: u16,             // the address of the instruction that failed
+    pub opcode: Option<u16>, // None if it couldn't even be fetched
+    pub v: [u8; 16],
+    pub i: usize,
+    pub sp: usize,
+    pub dt: u8,
+    pub st: u8,
+    pub history: Vec<(u16, u16)>, // address and opcode, oldest first, up to the one that failed
+}
+
+impl Crash {
+    pub fn new(cpu: &Cpu, error: CpuError, history: Vec<(u16, u16)>) -> Self {
+        let last = cpu.last_instruction();
+        Crash {
+            error,
+            pc: last.map_or(cpu.pc(), |(pc, _)| pc),
+            opcode: last.map(|(_, opcode)| opcode),
+            v: cpu.v(),
+            i: cpu.i(),
+            sp: cpu.sp(),
+            dt: cpu.dt(),
+            st: cpu.st(),
+            history,
+        }
+    }
+    pub fn lines(&self) -> Vec<String> {
+        let opcode = self.opcode.map_or("----".to_string(), |o| format!("{:04X}", o));
+        let registers = |range: std::ops::Range<usize>| {
+            range.map(|x| format!("V{:X} {:02X}", x, self.v[x])).collect::<Vec<_>>().join(" ")
+        };
+        let mut lines = vec![
+            self.error.to_string(),
+            format!(
+                "PC {:#05X}  Opcode {}  I {:#05X}  SP {}  DT {}  ST {}",
+                self.pc, opcode, self.i, self.sp, self.dt, self.st
+            ),
+            registers(0..8),
+            registers(8..16),
+        ];
+        if !self.history.is_empty() {
+            lines.push("Last instructions:".to_string());
+            for &(address, opcode) in &self.history {
+                lines.push(format!("  {:#05X}  {:04X}  {}", address, opcode, disassemble(opcode)));
+            }
+        }
+        lines
+    }
+}
+
+impl fmt::Display for Crash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.lines().join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::NullSink;
+    use crate::driver::Driver;
+    #[test]
+    fn test_crash_report() {
+        let mut driver = Driver::new(Cpu::new(), Box::new(NullSink::default()));
+        // CLS, LD V3, 0x2A, then an unknown opcode
+        driver.load_rom(vec![0x00, 0xE0, 0x63, 0x2A, 0x81, 0x28]).unwrap();
+        assert!(driver.run_frame().is_err());
+        assert!(driver.paused);
+        let crash = driver.crash.clone().unwrap();
+        assert_eq!((crash.pc, crash.opcode, crash.v[3]), (0x204, Some(0x8128), 0x2A));
+        let lines = crash.lines();
+        assert_eq!(lines[0], "Unknown OpCode: 0x8128");
+        assert!(lines[1].starts_with("PC 0x204  Opcode 8128"));
+        assert!(lines[2].contains("V3 2A"));
+        assert_eq!(lines[lines.len() - 1], "  0x204  8128  DW 0x8128");
+        assert_eq!(crash.history.len(), 3);
+        // Skipping it runs on from the next instruction, which is empty memory
+        driver.resume();
+        assert!(!driver.paused && driver.crash.is_none());
+        assert_eq!(driver.cpu.pc(), 0x206);
+        assert!(driver.run_frame().is_err());
+        // A reset starts the rom over
+        driver.reset();
+        assert!(!driver.paused && driver.crash.is_none());
+        assert_eq!(driver.cpu.pc(), 0x200);
+    }
+}
@@ -0,0 +1,156 @@
+// The roms the emulator knows, so they run with the right settings out of the box
+//
+// The database is built into the binary from database.toml. What it says
+// comes after the command line and the rom's settings in the config file,
+// but before the config's settings for all roms.
+use crate::cpu::Quirks;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+
+const DATABASE: &str = include_str!("database.toml");
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Database {
+    roms: BTreeMap<String, KnownRom>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KnownRom {
+    pub title: String,
+    pub authors: Vec<String>,
+    pub platform: Option<String>,
+    pub quirks: QuirkOverrides,
+    pub speed: Option<u32>,
+    pub keys: BTreeMap<String, u8>, // up, down, left, right, a or b -> hex key
+    pub colours: Option<Vec<String>>,
+}
+
+// Quirks a rom needs that differ from its platform's
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuirkOverrides {
+    pub shift: Option<bool>,
+    pub logic: Option<bool>,
+    pub memory_increment_by_x: Option<bool>,
+    pub memory_leave_i_unchanged: Option<bool>,
+    pub wrap: Option<bool>,
+    pub jump: Option<bool>,
+    pub vblank: Option<bool>,
+}
+
+impl QuirkOverrides {
+    pub fn apply(&self, quirks: &mut Quirks) {
+        let overrides = [
+            (self.shift, &mut quirks.shift),
+            (self.logic, &mut quirks.logic),
+            (self.memory_increment_by_x, &mut quirks.memory_increment_by_x),
+            (self.memory_leave_i_unchanged, &mut quirks.memory_leave_i_unchanged),
+            (self.wrap, &mut quirks.wrap),
+            (self.jump, &mut quirks.jump),
+            (self.vblank, &mut quirks.vblank),
+        ];
+        for (value, quirk) in overrides {
+            if let Some(value) = value {
+                *quirk = value;
+            }
+        }
+    }
+}
+
+// (action, keyboard keys, game controller buttons and stick directions)
+const ACTIONS: [(&str, &[&str], &[&str]); 6] = [
+    ("up", &["up"], &["dpup", "lefty-"]),
+    ("down", &["down"], &["dpdown", "lefty+"]),
+    ("left", &["left"], &["dpleft", "leftx-"]),
+    ("right", &["right"], &["dpright", "leftx+"]),
+    ("a", &["space"], &["a"]),
+    ("b", &["enter"], &["b"]),
+];
+
+impl KnownRom {
+    // The rom's keys on the arrows, space and enter, as hex key -> key names
+    pub fn keyboard_keys(&self) -> BTreeMap<u8, Vec<String>> {
+        self.bindings(false)
+    }
+    // The rom's keys on the D-pad, left stick, A and B
+    pub fn gamepad_keys(&self) -> BTreeMap<u8, Vec<String>> {
+        self.bindings(true)
+    }
+    fn bindings(&self, gamepad: bool) -> BTreeMap<u8, Vec<String>> {
+        let mut bindings: BTreeMap<u8, Vec<String>> = BTreeMap::new();
+        for (action, keys, buttons) in ACTIONS {
+            if let Some(&hex) = self.keys.get(action) {
+                let names = if gamepad { buttons } else { keys };
+                bindings.entry(hex).or_default().extend(names.iter().map(|k| k.to_string()));
+            }
+        }
+        bindings
+    }
+}
+
+// The database entry for a rom, by the SHA-1 of its file
+pub fn lookup(sha1: &str) -> Option<KnownRom> {
+    database().roms.get(sha1).cloned()
+}
+
+// Parsed the first time it's needed
+fn database() -> &'static Database {
+    static PARSED: OnceLock<Database> = OnceLock::new();
+    PARSED.get_or_init(|| toml::from_str(DATABASE).expect("The built-in rom database is broken"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Platform;
+    use crate::palette::Palette;
+    #[test]
+    fn test_database_is_valid() {
+        for (sha1, rom) in &database().roms {
+            assert!(sha1.len() == 40 && sha1.chars().all(|c| c.is_ascii_hexdigit()), "{}", sha1);
+            assert!(!rom.title.is_empty());
+            assert_ne!(rom.speed, Some(0), "{}", sha1);
+            if let Some(platform) = &rom.platform {
+                assert!(platform.parse::<Platform>().is_ok(), "{}", platform);
+            }
+            if let Some(colours) = &rom.colours {
+                assert!(Palette::custom(colours).is_ok());
+            }
+            for (action, &hex) in &rom.keys {
+                assert!(ACTIONS.iter().any(|(a, _, _)| a == action), "{}", action);
+                assert!(hex <= 0xF);
+            }
+        }
+    }
+    #[test]
+    fn test_bundled_roms_are_known() {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("roms");
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let rom = std::fs::read(entry.unwrap().path()).unwrap();
+            assert!(lookup(&sha1_smol::Sha1::from(&rom).digest().to_string()).is_some());
+        }
+    }
+    #[test]
+    fn test_lookup() {
+        let breakout = lookup("237756a4014fb3aa82a29246a7cdd534f8dc2dbb").unwrap();
+        assert_eq!(breakout.title, "Breakout");
+        assert_eq!(breakout.keyboard_keys()[&4], ["left"]);
+        assert_eq!(breakout.gamepad_keys()[&6], ["dpright", "leftx+"]);
+        assert!(lookup("0000000000000000000000000000000000000000").is_none());
+    }
+    #[test]
+    fn test_quirk_overrides() {
+        let mut quirks = Quirks::default();
+        let overrides = QuirkOverrides {
+            vblank: Some(true),
+            logic: Some(false),
+            ..Default::default()
+        };
+        overrides.apply(&mut quirks);
+        assert!(quirks.vblank && !quirks.logic);
+        assert_eq!(quirks.shift, Quirks::default().shift);
+    }
+}
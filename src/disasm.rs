@@ -0,0 +1,102 @@
+// Turning opcodes back into assembly, in the mnemonics of Cowgod's reference
+//
+// The listing of a rom is valid input for the assembler, so a rom can be
+// taken apart, edited and put back together. Anything the CPU doesn't run
+// comes out as DW, which is usually sprite data.
+use std::fmt::Write;
+
+const START: usize = 0x200;
+
+// "LD V1, 0x2A" for one opcode
+pub fn disassemble(opcode: u16) -> String {
+    let x = (opcode >> 8) & 0xF;
+    let y = (opcode >> 4) & 0xF;
+    let n = opcode & 0xF;
+    let nn = opcode & 0xFF;
+    let nnn = opcode & 0xFFF;
+    match (opcode >> 12, n) {
+        _ if opcode == 0x00E0 => "CLS".to_string(),
+        _ if opcode == 0x00EE => "RET".to_string(),
+        (0x1, _) => format!("JP {:#05X}", nnn),
+        (0x2, _) => format!("CALL {:#05X}", nnn),
+        (0x3, _) => format!("SE V{:X}, {:#04X}", x, nn),
+        (0x4, _) => format!("SNE V{:X}, {:#04X}", x, nn),
+        (0x5, 0x0) => format!("SE V{:X}, V{:X}", x, y),
+        (0x6, _) => format!("LD V{:X}, {:#04X}", x, nn),
+        (0x7, _) => format!("ADD V{:X}, {:#04X}", x, nn),
+        (0x8, 0x0) => format!("LD V{:X}, V{:X}", x, y),
+        (0x8, 0x1) => format!("OR V{:X}, V{:X}", x, y),
+        (0x8, 0x2) => format!("AND V{:X}, V{:X}", x, y),
+        (0x8, 0x3) => format!("XOR V{:X}, V{:X}", x, y),
+        (0x8, 0x4) => format!("ADD V{:X}, V{:X}", x, y),
+        (0x8, 0x5) => format!("SUB V{:X}, V{:X}", x, y),
+        (0x8, 0x6) => format!("SHR V{:X}, V{:X}", x, y),
+        (0x8, 0x7) => format!("SUBN V{:X}, V{:X}", x, y),
+        (0x8, 0xE) => format!("SHL V{:X}, V{:X}", x, y),
+        (0x9, 0x0) => format!("SNE V{:X}, V{:X}", x, y),
+        (0xA, _) => format!("LD I, {:#05X}", nnn),
+        (0xB, _) => format!("JP V0, {:#05X}", nnn),
+        (0xC, _) => format!("RND V{:X}, {:#04X}", x, nn),
+        (0xD, _) => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        (0xE, _) if nn == 0x9E => format!("SKP V{:X}", x),
+        (0xE, _) if nn == 0xA1 => format!("SKNP V{:X}", x),
+        (0xF, _) => match nn {
+            0x07 => format!("LD V{:X}, DT", x),
+            0x0A => format!("LD V{:X}, K", x),
+            0x15 => format!("LD DT, V{:X}", x),
+            0x18 => format!("LD ST, V{:X}", x),
+            0x1E => format!("ADD I, V{:X}", x),
+            0x29 => format!("LD F, V{:X}", x),
+            0x33 => format!("LD B, V{:X}", x),
+            0x55 => format!("LD [I], V{:X}", x),
+            0x65 => format!("LD V{:X}, [I]", x),
+            _ => format!("DW {:#06X}", opcode),
+        },
+        _ => format!("DW {:#06X}", opcode),
+    }
+}
+
+// The whole rom, one instruction per line with its address and opcode in a comment
+pub fn listing(rom: &[u8]) -> String {
+    let mut listing = String::new();
+    for (idx, bytes) in rom.chunks(2).enumerate() {
+        let address = START + idx * 2;
+        let (line, hex) = match *bytes {
+            [high, low] => {
+                let opcode = u16::from_be_bytes([high, low]);
+                (disassemble(opcode), format!("{:04X}", opcode))
+            }
+            // An odd byte at the end
+            [byte] => (format!("DB {:#04X}", byte), format!("{:02X}", byte)),
+            _ => unreachable!(),
+        };
+        writeln!(listing, "{:<20}; {:03X}: {}", line, address, hex).unwrap();
+    }
+    listing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_disassemble() {
+        assert_eq!(disassemble(0x00E0), "CLS");
+        assert_eq!(disassemble(0x1228), "JP 0x228");
+        assert_eq!(disassemble(0x6A2A), "LD VA, 0x2A");
+        assert_eq!(disassemble(0x8126), "SHR V1, V2");
+        assert_eq!(disassemble(0xD015), "DRW V0, V1, 5");
+        assert_eq!(disassemble(0xF365), "LD V3, [I]");
+        // Not instructions
+        assert_eq!(disassemble(0x0123), "DW 0x0123");
+        assert_eq!(disassemble(0x5121), "DW 0x5121");
+        assert_eq!(disassemble(0xFFFF), "DW 0xFFFF");
+    }
+    #[test]
+    fn test_listing() {
+        let listing = listing(&[0x00, 0xE0, 0x12, 0x00, 0xF0]);
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines[0], "CLS                 ; 200: 00E0");
+        assert_eq!(lines[1], "JP 0x200            ; 202: 1200");
+        assert_eq!(lines[2], "DB 0xF0             ; 204: F0");
+    }
+}
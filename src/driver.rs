@@ -0,0 +1,195 @@
+// Glue between the CPU and the outside world, shared by the frontends
+use crate::audio::AudioSink;
+use crate::cpu::{Cpu, CpuError};
+use crate::crash::{Crash, HISTORY_LENGTH};
+use crate::flicker::Flicker;
+use crate::record::Recorder;
+use crate::trace::Trace;
+use std::collections::VecDeque;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// How many frames fast-forward runs per frame
+pub const FAST_FORWARD_SPEED: u32 = 4;
+
+// Input besides the frontend's own keyboard, e.g. game controllers, polled once per frame
+pub trait InputSource {
+    fn poll(&mut self, cpu: &mut Cpu);
+}
+
+pub struct Driver {
+    pub cpu: Cpu,
+    pub sink: Box<dyn AudioSink>,
+    pub inputs: Vec<Box<dyn InputSource>>,
+    pub flicker: Flicker,
+    pub recorder: Option<Recorder>, // gets every frame while recording
+    pub trace: Option<Trace>,       // gets every instruction
+    pub paused: bool, // the frontends stop calling step and run_frame, except to single-step. See set_paused
+    pub fast_forward: bool, // the frontends run FAST_FORWARD_SPEED times as many cycles
+    pub crash: Option<Crash>, // why the driver paused itself, until reset or resume
+    history: VecDeque<(u16, u16)>, // the last instructions run, for the crash report
+    instructions: u64, // cycles run, for measuring the speed
+    rom: Vec<u8>,     // kept for resets, which wipe the memory
+    rom_path: Option<PathBuf>,
+}
+
+impl Driver {
+    pub fn new(cpu: Cpu, sink: Box<dyn AudioSink>) -> Self {
+        Driver {
+            cpu,
+            sink,
+            inputs: vec![],
+            flicker: Flicker::default(),
+            recorder: None,
+            trace: None,
+            paused: false,
+            fast_forward: false,
+            crash: None,
+            history: VecDeque::with_capacity(HISTORY_LENGTH),
+            instructions: 0,
+            rom: vec![],
+            rom_path: None,
+        }
+    }
+    // Reset the CPU and load a rom into it, which is kept for later resets
+    pub fn load_rom(&mut self, rom: Vec<u8>) -> Result<(), CpuError> {
+        self.cpu.reset();
+        if let Err(e) = self.cpu.load_rom(rom.clone()) {
+            // Too big, start the old one over rather than run half a rom
+            self.reset();
+            return Err(e);
+        }
+        self.rom = rom;
+        self.clear();
+        Ok(())
+    }
+    // Same as load_rom, but the file can be read again by reload
+    pub fn load_file(&mut self, path: &Path) -> Result<(), String> {
+        let rom = std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        self.load_rom(rom).map_err(|e| e.to_string())?;
+        self.rom_path = Some(path.to_path_buf());
+        Ok(())
+    }
+    // Read the rom from disk again and start over, e.g. after rebuilding it.
+    // If the file can't be read the running program is left alone
+    pub fn reload(&mut self) -> Result<(), String> {
+        match self.rom_path.clone() {
+            Some(path) => self.load_file(&path),
+            None => Err("The rom wasn't loaded from a file".to_string()),
+        }
+    }
+    // The SHA-1 of the rom in hex, which per-rom settings are keyed by
+    pub fn rom_sha1(&self) -> String {
+        sha1_smol::Sha1::from(&self.rom).digest().to_string()
+    }
+    // The rom's file name without the extension
+    pub fn rom_name(&self) -> Option<String> {
+        let path = self.rom_path.as_ref()?;
+        Some(path.file_stem()?.to_string_lossy().into_owned())
+    }
+    // How bright the frontends should draw a pixel, after flicker reduction
+    pub fn brightness(&self, x: usize, y: usize) -> f32 {
+        self.flicker.brightness(self.cpu.display(), x, y)
+    }
+    // Finish the recording, if there is one, and tell where it went
+    pub fn stop_recording(&mut self) -> Option<io::Result<PathBuf>> {
+        self.recorder.take().map(Recorder::finish)
+    }
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+    // Soft reset, the same rom starts over
+    pub fn reset(&mut self) {
+        self.cpu.reset();
+        // It fitted when it was loaded, so it still does
+        self.cpu.load_rom(self.rom.clone()).unwrap();
+        self.clear();
+    }
+    // What goes with the CPU when it starts over
+    fn clear(&mut self) {
+        self.sink.set_beep(false);
+        self.flicker.clear();
+        self.history.clear();
+        // A crash paused the driver, starting over unpauses it
+        if self.crash.take().is_some() {
+            self.paused = false;
+        }
+    }
+    // Skip the instruction that crashed and carry on
+    pub fn resume(&mut self) {
+        if let Some(crash) = self.crash.take() {
+            self.cpu.jump(crash.pc.wrapping_add(2));
+            self.set_paused(false);
+        }
+    }
+    // Pausing silences the beep, unpausing plays it again if the sound timer is still running
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        self.update_beep();
+    }
+    fn update_beep(&mut self) {
+        let beep = self.cpu.beep && !self.paused;
+        self.sink.set_beep(beep);
+        if let Some(recorder) = &mut self.recorder {
+            recorder.set_beep(beep);
+        }
+    }
+    pub fn poll_inputs(&mut self) {
+        for input in self.inputs.iter_mut() {
+            input.poll(&mut self.cpu);
+        }
+    }
+    // Run one cycle and keep the sound in step with the emulated frames,
+    // so audio rendered by the sink lines up with the timers to the sample
+    pub fn step(&mut self) -> Result<(), CpuError> {
+        let frame = self.cpu.frames();
+        let result = self.cpu.emulate_cycle().map(|()| 1);
+        self.ran(frame, result)
+    }
+    // Catch up with the cycles the CPU just ran, which started in the given frame
+    fn ran(&mut self, frame: u64, result: Result<u32, CpuError>) -> Result<(), CpuError> {
+        // Kept before the error is passed on, the failed instruction is the interesting one
+        for &(address, opcode) in self.cpu.last_instructions() {
+            if let Some(trace) = &mut self.trace {
+                trace.instruction(self.cpu.frames(), address, opcode);
+            }
+            if self.history.len() == HISTORY_LENGTH {
+                self.history.pop_front();
+            }
+            self.history.push_back((address, opcode));
+        }
+        let cycles = match result {
+            Ok(cycles) => cycles,
+            Err(e) => {
+                self.crash = Some(Crash::new(&self.cpu, e.clone(), self.history.iter().copied().collect()));
+                // No tone left running under the crash report
+                self.set_paused(true);
+                return Err(e);
+            }
+        };
+        self.instructions += cycles as u64;
+        // Single-stepping while paused stays quiet
+        self.update_beep();
+        if self.cpu.frames() != frame {
+            self.sink.end_frame();
+            self.flicker.vblank(self.cpu.display());
+            // Out of the driver for a moment, as it looks at the whole driver
+            if let Some(mut recorder) = self.recorder.take() {
+                recorder.frame(self);
+                self.recorder = Some(recorder);
+            }
+        }
+        Ok(())
+    }
+    // Poll the inputs, then run cycles until the timers tick once. The same as stepping, but the
+    // recompiler gets to run whole blocks
+    pub fn run_frame(&mut self) -> Result<(), CpuError> {
+        self.poll_inputs();
+        let frame = self.cpu.frames();
+        while self.cpu.frames() == frame {
+            let result = self.cpu.emulate_cycles();
+            self.ran(frame, result)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+// Flicker reduction between the CPU's display and the frontends
+//
+// Games move a sprite by XORing it away and drawing it again, so a screen
+// caught in between shows it missing. The filters look at the display once
+// per 60 Hz frame, when the VIP would have sent the picture to the TV:
+//
+//     vblank    the display as it was at the last frame
+//     or        a pixel is lit if it was at either of the last two frames
+//     phosphor  lit pixels fade out over a few frames, like on a CRT
+use crate::cpu::{CHIP_8_HEIGHT, CHIP_8_WIDTH};
+use crate::framebuffer::Framebuffer;
+use std::str::FromStr;
+
+const PHOSPHOR_DECAY: f32 = 0.5; // brightness a pixel keeps each frame after it goes off
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlickerMode {
+    Off, // the display as it is when drawn
+    Vblank,
+    Or,
+    Phosphor,
+}
+
+impl FromStr for FlickerMode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(FlickerMode::Off),
+            "vblank" => Ok(FlickerMode::Vblank),
+            "or" => Ok(FlickerMode::Or),
+            "phosphor" => Ok(FlickerMode::Phosphor),
+            _ => Err(format!("Unknown flicker mode: {}", s)),
+        }
+    }
+}
+
+pub struct Flicker {
+    mode: FlickerMode,
+    last: Framebuffer,     // the display at the last frame
+    previous: Framebuffer, // and at the one before
+    glow: Vec<f32>,        // row by row
+}
+
+impl Flicker {
+    pub fn new(mode: FlickerMode) -> Self {
+        Flicker {
+            mode,
+            last: Framebuffer::default(),
+            previous: Framebuffer::default(),
+            glow: vec![0.0; CHIP_8_WIDTH * CHIP_8_HEIGHT],
+        }
+    }
+    pub fn mode(&self) -> FlickerMode {
+        self.mode
+    }
+    // Forget the old frames, e.g. after a reset
+    pub fn clear(&mut self) {
+        self.last.clear();
+        self.previous.clear();
+        self.glow.fill(0.0);
+    }
+    // Called once per 60 Hz frame
+    pub fn vblank(&mut self, display: &Framebuffer) {
+        self.previous = std::mem::replace(&mut self.last, display.clone());
+        if self.mode != FlickerMode::Phosphor {
+            return;
+        }
+        for y in 0..CHIP_8_HEIGHT {
+            for x in 0..CHIP_8_WIDTH {
+                let idx = y * CHIP_8_WIDTH + x;
+                self.glow[idx] = if display.get(x, y) { 1.0 } else { self.glow[idx] * PHOSPHOR_DECAY };
+            }
+        }
+    }
+    // How bright a pixel should be drawn, from 0.0 (background) to 1.0 (foreground)
+    pub fn brightness(&self, display: &Framebuffer, x: usize, y: usize) -> f32 {
+        let lit = match self.mode {
+            FlickerMode::Off => display.get(x, y),
+            FlickerMode::Vblank => self.last.get(x, y),
+            FlickerMode::Or => self.last.get(x, y) || self.previous.get(x, y),
+            FlickerMode::Phosphor => return self.glow[y * CHIP_8_WIDTH + x],
+        };
+        if lit {
+            1.0
+        } else {
+            0.0
+        }
+    }
+}
+
+impl Default for Flicker {
+    fn default() -> Self {
+        Self::new(FlickerMode::Off)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn display(lit: bool) -> Framebuffer {
+        let mut display = Framebuffer::default();
+        display.set(3, 4, lit);
+        display
+    }
+    #[test]
+    fn test_vblank_holds_the_last_frame() {
+        let mut flicker = Flicker::new(FlickerMode::Vblank);
+        flicker.vblank(&display(true));
+        // The sprite is erased halfway through the next frame
+        assert_eq!(flicker.brightness(&display(false), 3, 4), 1.0);
+        flicker.vblank(&display(false));
+        assert_eq!(flicker.brightness(&display(false), 3, 4), 0.0);
+        assert_eq!(Flicker::default().brightness(&display(true), 3, 4), 1.0);
+    }
+    #[test]
+    fn test_or_keeps_two_frames() {
+        let mut flicker = Flicker::new(FlickerMode::Or);
+        flicker.vblank(&display(true));
+        flicker.vblank(&display(false));
+        assert_eq!(flicker.brightness(&display(false), 3, 4), 1.0);
+        flicker.vblank(&display(false));
+        assert_eq!(flicker.brightness(&display(false), 3, 4), 0.0);
+    }
+    #[test]
+    fn test_phosphor_decays() {
+        let mut flicker = Flicker::new(FlickerMode::Phosphor);
+        flicker.vblank(&display(true));
+        assert_eq!(flicker.brightness(&display(true), 3, 4), 1.0);
+        flicker.vblank(&display(false));
+        flicker.vblank(&display(false));
+        assert_eq!(flicker.brightness(&display(false), 3, 4), 0.25);
+        flicker.clear();
+        assert_eq!(flicker.brightness(&display(false), 3, 4), 0.0);
+        assert_eq!("phosphor".parse(), Ok(FlickerMode::Phosphor));
+        assert!("blur".parse::<FlickerMode>().is_err());
+    }
+}
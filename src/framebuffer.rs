@@ -0,0 +1,136 @@
+// The screen the CPU draws on, packed one bit per pixel
+//
+// Every row is a u128 with the leftmost pixel in the top bit, so a sprite
+// row goes on with a shift and an XOR and a collision is an AND. That is
+// wide enough for the 128x64 of SCHIP's high resolution.
+use crate::cpu::{CHIP_8_HEIGHT, CHIP_8_WIDTH};
+
+pub const MAX_WIDTH: usize = 128;
+pub const MAX_HEIGHT: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Framebuffer {
+    width: usize,
+    height: usize,
+    rows: [u128; MAX_HEIGHT], // only the first `height`, and the top `width` bits of each, are used
+}
+
+impl Default for Framebuffer {
+    fn default() -> Self {
+        Self::new(CHIP_8_WIDTH, CHIP_8_HEIGHT)
+    }
+}
+
+impl Framebuffer {
+    // A blank screen, at most MAX_WIDTH by MAX_HEIGHT
+    pub fn new(width: usize, height: usize) -> Self {
+        assert!(width > 0 && width <= MAX_WIDTH && height > 0 && height <= MAX_HEIGHT);
+        Framebuffer {
+            width,
+            height,
+            rows: [0; MAX_HEIGHT],
+        }
+    }
+    pub fn width(&self) -> usize {
+        self.width
+    }
+    pub fn height(&self) -> usize {
+        self.height
+    }
+    pub fn get(&self, x: usize, y: usize) -> bool {
+        x < self.width && y < self.height && self.rows[y] & bit(x) != 0
+    }
+    pub fn set(&mut self, x: usize, y: usize, lit: bool) {
+        if x < self.width && y < self.height {
+            if lit {
+                self.rows[y] |= bit(x);
+            } else {
+                self.rows[y] &= !bit(x);
+            }
+        }
+    }
+    // A row of pixels, the leftmost in the top bit
+    pub fn row(&self, y: usize) -> u128 {
+        self.rows[y]
+    }
+    pub fn rows(&self) -> &[u128] {
+        &self.rows[..self.height]
+    }
+    pub fn clear(&mut self) {
+        self.rows.fill(0);
+    }
+    // XOR an 8 pixel wide sprite onto the screen with its top left corner at (x, y), which have to
+    // be on the screen. What goes past the edges is clipped or wraps around. True if a lit pixel was
+    // turned off
+    pub fn draw_sprite(&mut self, x: usize, y: usize, sprite: &[u8], wrap: bool) -> bool {
+        let visible = !0u128 << (MAX_WIDTH - self.width);
+        let mut collision = false;
+        for (r, &byte) in sprite.iter().enumerate() {
+            let mut row_y = y + r;
+            if row_y >= self.height {
+                if !wrap {
+                    break;
+                }
+                row_y %= self.height;
+            }
+            let left = (byte as u128) << (MAX_WIDTH - 8);
+            let mut bits = (left >> x) & visible;
+            if wrap && x + 8 > self.width {
+                // The pixels past the right edge come back in on the left
+                bits |= left << (self.width - x);
+            }
+            collision |= self.rows[row_y] & bits != 0;
+            self.rows[row_y] ^= bits;
+        }
+        collision
+    }
+}
+
+fn bit(x: usize) -> u128 {
+    1 << (MAX_WIDTH - 1 - x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_get_and_set() {
+        let mut screen = Framebuffer::default();
+        screen.set(0, 0, true);
+        screen.set(63, 31, true);
+        screen.set(64, 0, true); // off the screen
+        assert!(screen.get(0, 0) && screen.get(63, 31));
+        assert!(!screen.get(1, 0) && !screen.get(64, 0));
+        assert_eq!(screen.row(0), 1 << 127);
+        assert_eq!(screen.rows().len(), 32);
+        screen.set(0, 0, false);
+        assert!(!screen.get(0, 0));
+        screen.clear();
+        assert_eq!(screen, Framebuffer::default());
+    }
+    #[test]
+    fn test_sprites_xor_and_collide() {
+        let mut screen = Framebuffer::default();
+        assert!(!screen.draw_sprite(3, 2, &[0b1000_0001, 0xFF], false));
+        assert!(screen.get(3, 2) && screen.get(10, 2) && !screen.get(4, 2));
+        assert!((3..11).all(|x| screen.get(x, 3)));
+        // Drawing it again erases it
+        assert!(screen.draw_sprite(3, 2, &[0b1000_0001, 0xFF], false));
+        assert_eq!(screen, Framebuffer::default());
+    }
+    #[test]
+    fn test_sprites_clip_or_wrap() {
+        let mut clipped = Framebuffer::default();
+        clipped.draw_sprite(60, 31, &[0xFF, 0xFF], false);
+        assert!((60..64).all(|x| clipped.get(x, 31)));
+        assert!(!clipped.get(0, 31) && !clipped.get(0, 0) && clipped.rows()[0] == 0);
+        let mut wrapped = Framebuffer::default();
+        wrapped.draw_sprite(60, 31, &[0xFF, 0xFF], true);
+        assert!((60..64).chain(0..4).all(|x| wrapped.get(x, 31) && wrapped.get(x, 0)));
+        assert!(!wrapped.get(4, 31) && !wrapped.get(59, 0));
+        // High resolution
+        let mut hires = Framebuffer::new(128, 64);
+        hires.draw_sprite(124, 63, &[0xFF], true);
+        assert!(hires.get(127, 63) && hires.get(3, 63) && !hires.get(4, 63));
+    }
+}
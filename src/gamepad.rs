@@ -0,0 +1,186 @@
+// Game controllers driving the hex keypad
+//
+// Buttons are bound through a Keymap by their SDL names ("a", "dpup", ...),
+// and each stick direction acts as a button ("leftx-", "lefty+", ...) once it
+// is pushed past the threshold.
+use crate::cpu::Cpu;
+use crate::keymap::Keymap;
+
+pub const DEFAULT_THRESHOLD: f32 = 0.5;
+// A stick has to come back this far below the threshold before it lets go,
+// so a stick resting right at the threshold doesn't chatter
+const HYSTERESIS: f32 = 0.1;
+
+pub struct PadMapper {
+    keymap: Keymap,
+    threshold: f32,
+    held: Vec<String>, // buttons and stick directions that are down
+    counts: [u8; 16],  // how many held inputs press each hex key
+}
+
+impl PadMapper {
+    pub fn new(keymap: Keymap, threshold: f32) -> Self {
+        PadMapper {
+            keymap,
+            threshold,
+            held: vec![],
+            counts: [0; 16],
+        }
+    }
+    pub fn button(&mut self, name: &str, down: bool, cpu: &mut Cpu) {
+        let held = self.held.iter().position(|b| b == name);
+        let hex = self.keymap.lookup(Some(name), None);
+        match (down, held) {
+            (true, None) => {
+                self.held.push(name.to_string());
+                if let Some(k) = hex {
+                    self.counts[k as usize] += 1;
+                    if self.counts[k as usize] == 1 {
+                        cpu.press_key(k);
+                    }
+                }
+            }
+            (false, Some(idx)) => {
+                self.held.remove(idx);
+                if let Some(k) = hex {
+                    self.counts[k as usize] -= 1;
+                    if self.counts[k as usize] == 0 {
+                        cpu.release_key(k);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    // value goes from -1.0 to 1.0
+    pub fn axis(&mut self, name: &str, value: f32, cpu: &mut Cpu) {
+        for (direction, sign) in [("-", -1.0), ("+", 1.0)] {
+            let button = format!("{}{}", name, direction);
+            let pushed = value * sign;
+            if pushed >= self.threshold {
+                self.button(&button, true, cpu);
+            } else if pushed < self.threshold - HYSTERESIS {
+                self.button(&button, false, cpu);
+            }
+        }
+    }
+    // Lets go of everything, e.g. when the controller is unplugged
+    pub fn release_all(&mut self, cpu: &mut Cpu) {
+        for name in self.held.clone() {
+            self.button(&name, false, cpu);
+        }
+    }
+}
+
+// Game controllers through SDL2, needs the `sdl` feature and libSDL2
+#[cfg(feature = "sdl")]
+pub struct SdlGamepads {
+    subsystem: sdl2::GameControllerSubsystem,
+    pump: sdl2::EventPump,
+    // Each controller gets its own mapper, so unplugging one only lets go of its keys
+    controllers: Vec<(sdl2::controller::GameController, PadMapper)>,
+    keymap: Keymap,
+    threshold: f32,
+}
+
+#[cfg(feature = "sdl")]
+impl SdlGamepads {
+    pub fn open(sdl: &sdl2::Sdl, keymap: Keymap, threshold: f32) -> Result<Self, String> {
+        // The window belongs to piston, so SDL never thinks it has focus
+        sdl2::hint::set("SDL_JOYSTICK_ALLOW_BACKGROUND_EVENTS", "1");
+        Ok(SdlGamepads {
+            subsystem: sdl.game_controller()?,
+            pump: sdl.event_pump()?,
+            controllers: vec![],
+            keymap,
+            threshold,
+        })
+    }
+}
+
+#[cfg(feature = "sdl")]
+impl crate::driver::InputSource for SdlGamepads {
+    fn poll(&mut self, cpu: &mut Cpu) {
+        use sdl2::event::Event;
+        // Controllers plugged in at startup are reported as added too
+        for event in self.pump.poll_iter().collect::<Vec<_>>() {
+            match event {
+                Event::ControllerDeviceAdded { which, .. } => match self.subsystem.open(which) {
+                    Ok(controller) => {
+                        let mapper = PadMapper::new(self.keymap.clone(), self.threshold);
+                        self.controllers.push((controller, mapper));
+                    }
+                    Err(e) => eprintln!("Failed to open controller: {}", e),
+                },
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    if let Some(idx) = self.controllers.iter().position(|(c, _)| c.instance_id() == which) {
+                        let (_, mut mapper) = self.controllers.remove(idx);
+                        mapper.release_all(cpu);
+                    }
+                }
+                Event::ControllerButtonDown { which, button, .. } => {
+                    if let Some((_, mapper)) = self.controllers.iter_mut().find(|(c, _)| c.instance_id() == which) {
+                        mapper.button(&button.string(), true, cpu);
+                    }
+                }
+                Event::ControllerButtonUp { which, button, .. } => {
+                    if let Some((_, mapper)) = self.controllers.iter_mut().find(|(c, _)| c.instance_id() == which) {
+                        mapper.button(&button.string(), false, cpu);
+                    }
+                }
+                Event::ControllerAxisMotion { which, axis, value, .. } => {
+                    if let Some((_, mapper)) = self.controllers.iter_mut().find(|(c, _)| c.instance_id() == which) {
+                        mapper.axis(&axis.string(), value as f32 / i16::MAX as f32, cpu);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_buttons_press_keys() {
+        let mut cpu = Cpu::new();
+        let mut pad = PadMapper::new(Keymap::gamepad(), DEFAULT_THRESHOLD);
+        pad.button("a", true, &mut cpu);
+        assert!(cpu.key_down(0x5));
+        pad.button("a", false, &mut cpu);
+        assert!(!cpu.key_down(0x5));
+        // Unbound buttons do nothing
+        pad.button("guide", true, &mut cpu);
+        assert!((0..16).all(|k| !cpu.key_down(k)));
+    }
+    #[test]
+    fn test_stick_threshold() {
+        let mut cpu = Cpu::new();
+        let mut pad = PadMapper::new(Keymap::gamepad(), DEFAULT_THRESHOLD);
+        pad.axis("leftx", -0.3, &mut cpu);
+        assert!(!cpu.key_down(0x4));
+        pad.axis("leftx", -0.7, &mut cpu);
+        assert!(cpu.key_down(0x4));
+        // Just under the threshold still counts as held
+        pad.axis("leftx", -0.45, &mut cpu);
+        assert!(cpu.key_down(0x4));
+        pad.axis("leftx", 0.9, &mut cpu);
+        assert!(!cpu.key_down(0x4));
+        assert!(cpu.key_down(0x6));
+    }
+    #[test]
+    fn test_shared_key_and_unplug() {
+        let mut cpu = Cpu::new();
+        let mut pad = PadMapper::new(Keymap::gamepad(), DEFAULT_THRESHOLD);
+        // The D-pad and the stick both press 4, it stays down until both let go
+        pad.button("dpleft", true, &mut cpu);
+        pad.axis("leftx", -1.0, &mut cpu);
+        pad.button("dpleft", false, &mut cpu);
+        assert!(cpu.key_down(0x4));
+        pad.button("a", true, &mut cpu);
+        pad.release_all(&mut cpu);
+        assert!(!cpu.key_down(0x4));
+        assert!(!cpu.key_down(0x5));
+    }
+}
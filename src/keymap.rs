@@ -0,0 +1,213 @@
+// Mapping from physical keys to the hex keypad
+//
+// The CHIP-8 keypad is a 4x4 grid, which is laid over the 1234 block of a
+// keyboard by default:
+//
+//     1 2 3 C        1 2 3 4
+//     4 5 6 D   <-   Q W E R
+//     7 8 9 E        A S D F
+//     A 0 B F        Z X C V
+//
+// Keys are bound either by name ("q", "left", "space"), which follows the
+// keyboard layout, or by scancode, which follows the position on the keyboard.
+use crate::config::KeymapConfig;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Binding {
+    Key(String),
+    Scancode(i32),
+}
+
+impl Binding {
+    // "scancode:16" or a key name
+    pub fn parse(s: &str) -> Result<Binding, String> {
+        match s.strip_prefix("scancode:") {
+            Some(code) => code
+                .parse()
+                .map(Binding::Scancode)
+                .map_err(|_| format!("Invalid scancode: {}", s)),
+            None if !s.is_empty() => Ok(Binding::Key(s.to_lowercase())),
+            None => Err("Empty key name".to_string()),
+        }
+    }
+}
+
+// Hex keys in the order of the 4x4 grid, row by row
+const GRID: [u8; 16] = [0x1, 0x2, 0x3, 0xC, 0x4, 0x5, 0x6, 0xD, 0x7, 0x8, 0x9, 0xE, 0xA, 0x0, 0xB, 0xF];
+// The keys covering the grid on each layout
+const QWERTY: [&str; 16] = ["1", "2", "3", "4", "q", "w", "e", "r", "a", "s", "d", "f", "z", "x", "c", "v"];
+const AZERTY: [&str; 16] = ["1", "2", "3", "4", "a", "z", "e", "r", "q", "s", "d", "f", "w", "x", "c", "v"];
+const DVORAK: [&str; 16] = ["1", "2", "3", "4", "'", ",", ".", "p", "a", "o", "e", "u", ";", "q", "j", "k"];
+// Scancodes of the QWERTY keys, the same spot on every layout
+const SCANCODES: [i32; 16] = [2, 3, 4, 5, 16, 17, 18, 19, 30, 31, 32, 33, 44, 45, 46, 47];
+// Game controller buttons, the D-pad and left stick are the 2/4/6/8 arrows most games use
+const GAMEPAD: [(&str, u8); 9] = [
+    ("dpup", 0x2),
+    ("dpleft", 0x4),
+    ("dpright", 0x6),
+    ("dpdown", 0x8),
+    ("lefty-", 0x2),
+    ("leftx-", 0x4),
+    ("leftx+", 0x6),
+    ("lefty+", 0x8),
+    ("a", 0x5),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Keymap {
+    bindings: Vec<(Binding, u8)>, // a hex key may have several bindings
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Self::preset("qwerty").unwrap()
+    }
+}
+
+impl Keymap {
+    // qwerty, azerty and dvorak bind by name, scancode binds by position
+    pub fn preset(name: &str) -> Result<Keymap, String> {
+        let bindings: Vec<Binding> = match name {
+            "qwerty" => QWERTY.iter().map(|k| Binding::Key(k.to_string())).collect(),
+            "azerty" => AZERTY.iter().map(|k| Binding::Key(k.to_string())).collect(),
+            "dvorak" => DVORAK.iter().map(|k| Binding::Key(k.to_string())).collect(),
+            "scancode" => SCANCODES.iter().map(|&k| Binding::Scancode(k)).collect(),
+            _ => return Err(format!("Unknown keymap: {}", name)),
+        };
+        Ok(Keymap {
+            bindings: bindings.into_iter().zip(GRID).collect(),
+        })
+    }
+    // Game controller buttons and stick directions ("leftx-", "lefty+", ...)
+    pub fn gamepad() -> Keymap {
+        Keymap {
+            bindings: GAMEPAD.iter().map(|&(b, k)| (Binding::Key(b.to_string()), k)).collect(),
+        }
+    }
+    pub fn from_config(config: &KeymapConfig) -> Result<Keymap, String> {
+        let mut keymap = Self::preset(config.preset.as_deref().unwrap_or("qwerty"))?;
+        keymap.apply_keys(&config.keys)?;
+        Ok(keymap)
+    }
+    // Applies a config's hex key -> keys table
+    pub fn apply_keys(&mut self, keys: &BTreeMap<String, Vec<String>>) -> Result<(), String> {
+        for (hex, keys) in keys {
+            let bindings = keys.iter().map(|k| Binding::parse(k)).collect::<Result<Vec<_>, _>>()?;
+            self.rebind(parse_hex(hex)?, bindings);
+        }
+        Ok(())
+    }
+    // Replaces the bindings of a hex key, the keys given are taken from whatever they were bound to
+    pub fn rebind(&mut self, hex: u8, bindings: Vec<Binding>) {
+        self.bindings.retain(|(b, k)| *k != hex && !bindings.contains(b));
+        self.bindings.extend(bindings.into_iter().map(|b| (b, hex)));
+    }
+    // Binds more keys to a hex key, which keeps the ones it had
+    pub fn add(&mut self, hex: u8, bindings: Vec<Binding>) {
+        self.bindings.retain(|(b, _)| !bindings.contains(b));
+        self.bindings.extend(bindings.into_iter().map(|b| (b, hex)));
+    }
+    // Applies "HEX=KEY" bindings from the command line, several for one hex key add up
+    pub fn apply_binds(&mut self, binds: &[String]) -> Result<(), String> {
+        let mut rebound: Vec<(u8, Vec<Binding>)> = vec![];
+        for bind in binds {
+            let (hex, key) = bind.split_once('=').ok_or_else(|| format!("Expected HEX=KEY: {}", bind))?;
+            let hex = parse_hex(hex)?;
+            let binding = Binding::parse(key)?;
+            match rebound.iter_mut().find(|(k, _)| *k == hex) {
+                Some((_, bindings)) => bindings.push(binding),
+                None => rebound.push((hex, vec![binding])),
+            }
+        }
+        for (hex, bindings) in rebound {
+            self.rebind(hex, bindings);
+        }
+        Ok(())
+    }
+    // The hex key bound to a key name or a scancode
+    pub fn lookup(&self, name: Option<&str>, scancode: Option<i32>) -> Option<u8> {
+        self.bindings.iter().find_map(|(binding, hex)| {
+            let matched = match binding {
+                Binding::Key(k) => Some(k.as_str()) == name,
+                Binding::Scancode(s) => Some(*s) == scancode,
+            };
+            matched.then_some(*hex)
+        })
+    }
+}
+
+fn parse_hex(s: &str) -> Result<u8, String> {
+    match u8::from_str_radix(s, 16) {
+        Ok(k) if k <= 0xF => Ok(k),
+        _ => Err(format!("Invalid hex key: {}", s)),
+    }
+}
+
+// The name a typed character is bound by
+pub fn char_name(c: char) -> String {
+    match c {
+        ' ' => "space".to_string(),
+        _ => c.to_lowercase().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_default_keymap() {
+        let keymap = Keymap::default();
+        assert_eq!(keymap.lookup(Some("1"), None), Some(0x1));
+        assert_eq!(keymap.lookup(Some("v"), None), Some(0xF));
+        assert_eq!(keymap.lookup(Some("x"), None), Some(0x0));
+        assert_eq!(keymap.lookup(Some("p"), None), None);
+        // Scancodes only count with the scancode preset
+        assert_eq!(keymap.lookup(None, Some(16)), None);
+        assert_eq!(Keymap::preset("scancode").unwrap().lookup(None, Some(16)), Some(0x4));
+    }
+    #[test]
+    fn test_layout_presets() {
+        let azerty = Keymap::preset("azerty").unwrap();
+        assert_eq!(azerty.lookup(Some("a"), None), Some(0x4));
+        assert_eq!(azerty.lookup(Some("q"), None), Some(0x7));
+        let dvorak = Keymap::preset("dvorak").unwrap();
+        assert_eq!(dvorak.lookup(Some("o"), None), Some(0x8));
+        assert!(Keymap::preset("colemak").is_err());
+    }
+    #[test]
+    fn test_binds() {
+        let mut keymap = Keymap::default();
+        keymap.apply_binds(&["4=left".to_string(), "6=right".to_string(), "6=d".to_string()]).unwrap();
+        assert_eq!(keymap.lookup(Some("left"), None), Some(0x4));
+        assert_eq!(keymap.lookup(Some("right"), None), Some(0x6));
+        // d is taken away from 9
+        assert_eq!(keymap.lookup(Some("d"), None), Some(0x6));
+        // The old binding of 4 is gone, the other keys are untouched
+        assert_eq!(keymap.lookup(Some("q"), None), None);
+        assert_eq!(keymap.lookup(Some("w"), None), Some(0x5));
+        assert!(keymap.apply_binds(&["G=left".to_string()]).is_err());
+        assert!(keymap.apply_binds(&["4".to_string()]).is_err());
+    }
+    #[test]
+    fn test_add() {
+        let mut keymap = Keymap::default();
+        keymap.add(0x4, vec![Binding::Key("left".to_string()), Binding::Key("w".to_string())]);
+        assert_eq!(keymap.lookup(Some("left"), None), Some(0x4));
+        assert_eq!(keymap.lookup(Some("q"), None), Some(0x4));
+        assert_eq!(keymap.lookup(Some("w"), None), Some(0x4));
+    }
+    #[test]
+    fn test_keymap_from_config() {
+        let mut config = KeymapConfig {
+            preset: Some("azerty".to_string()),
+            ..Default::default()
+        };
+        config.keys.insert("5".to_string(), vec!["space".to_string(), "scancode:57".to_string()]);
+        let keymap = Keymap::from_config(&config).unwrap();
+        assert_eq!(keymap.lookup(Some("space"), None), Some(0x5));
+        assert_eq!(keymap.lookup(None, Some(57)), Some(0x5));
+        assert_eq!(keymap.lookup(Some("z"), None), None);
+        assert_eq!(keymap.lookup(Some("a"), None), Some(0x4));
+    }
+}
@@ -0,0 +1,24 @@
+// The emulator core, shared by the binary and the integration tests
+pub mod analyze;
+pub mod asm;
+pub mod audio;
+pub mod bench;
+pub mod capture;
+pub mod config;
+pub mod cpu;
+pub mod crash;
+pub mod database;
+pub mod disasm;
+pub mod driver;
+pub mod flicker;
+pub mod fonts;
+pub mod framebuffer;
+pub mod gamepad;
+pub mod keymap;
+pub mod osd;
+pub mod palette;
+pub mod record;
+pub mod scheduler;
+pub mod trace;
+
+pub const UPDATE_RATE: u64 = 500; // I need 500hz for the CPU
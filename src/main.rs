@@ -1,100 +1,739 @@
 // My own modules
-mod cpu;
-mod fonts;
-use cpu::{Cpu, CHIP_8_HEIGHT, CHIP_8_WIDTH};
+mod terminal;
+mod window;
+use chip8::cpu::{Cpu, Engine, Platform, Quirks, DEFAULT_SPEED};
+use chip8::audio::{AudioSink, NullSink, ToneSettings, WavSink, Waveform};
+use chip8::capture::{self, ScreenshotSettings};
+use chip8::config::{Config, RomConfig};
+use chip8::database::{self, KnownRom};
+use chip8::driver::Driver;
+use chip8::flicker::{Flicker, FlickerMode};
+use chip8::keymap::{Binding, Keymap};
+use chip8::osd::Osd;
+use chip8::palette::{Palette, PaletteCycle};
+use chip8::record::{RecordFormat, RecordSettings, Recorder};
+use chip8::trace::Trace;
+use chip8::{analyze, asm, bench, disasm};
+use terminal::Glyphs;
+use window::{Scaling, WindowOptions};
 
-extern crate glutin_window;
-extern crate piston;
-extern crate graphics;
-extern crate opengl_graphics;
-// Import for reading the command line argument
+// Import for reading the command line arguments
+use clap::{Args, CommandFactory, Parser, Subcommand};
 use std::env;
-// Imports for reading files
-use std::fs::File;
-use std::io::prelude::*;
-use piston::{RenderEvent, WindowSettings, ButtonEvent, UpdateEvent};
-use glutin_window::GlutinWindow;
-use piston::event_loop::{EventSettings, Events};
-use opengl_graphics::{GlGraphics, OpenGL};
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+#[derive(Parser)]
+#[command(
+    about = "A CHIP-8 emulator",
+    after_help = "Without a command, e.g. `chip8 game.ch8`, the rom is run",
+    arg_required_else_help = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Play a rom in a window, or in the terminal
+    Run(RunOptions),
+    /// Run a rom without a window for a number of frames
+    Headless(HeadlessOptions),
+    /// Print a rom as assembly, which asm turns back into the same rom
+    Disasm {
+        /// Path to the rom
+        rom: PathBuf,
+        /// Where the listing goes, instead of standard output
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Assemble a source file into a rom
+    Asm {
+        /// Path to the source
+        source: PathBuf,
+        /// Where the rom goes, defaults to the source with a .ch8 extension
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Show what is known about a rom and the settings it runs with
+    Info(InfoOptions),
+    /// Run a rom without a window and print the screen at the end, or compare it with an expected one
+    Test(TestOptions),
+    /// Measure the instructions per second of each engine on synthetic workloads and roms
+    Bench(BenchOptions),
+    /// Find a rom's code, subroutines, loops, computed jumps and self-modifying stores
+    Analyze {
+        /// Path to the rom
+        rom: PathBuf,
+        /// Write the control-flow graph in Graphviz's DOT format to this file
+        #[arg(long, value_name = "FILE")]
+        dot: Option<PathBuf>,
+    },
+}
+
+// What every subcommand that runs a rom needs
+#[derive(Args)]
+struct MachineOptions {
+    /// Instructions per 60 Hz frame, 8 by default
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    speed: Option<u32>,
+    /// How instructions are run: interpreter, cached to decode each one once, or recompiler to run
+    /// straight-line code in blocks
+    #[arg(long, default_value = "interpreter")]
+    engine: Engine,
+    /// The machine the rom was written for, which picks its quirks: chip-8, schip or xo-chip
+    #[arg(long)]
+    platform: Option<Platform>,
+    /// Quirk profile, when it isn't the platform's: chip-8, vip, schip or xo-chip
+    #[arg(long)]
+    quirks: Option<String>,
+    /// Seed for the random numbers, the same seed gives the same game. Only run picks one by itself
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Write every instruction run to a file
+    #[arg(long)]
+    trace: Option<PathBuf>,
+    /// Config file, defaults to ~/.config/chip8/config.toml
+    #[arg(long)]
+    config: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct SoundOptions {
+    /// Beep frequency in Hz
+    #[arg(long, default_value_t = 440.0)]
+    frequency: f32,
+    /// Beep volume, between 0 and 1
+    #[arg(long, default_value_t = 0.25)]
+    volume: f32,
+    /// Beep waveform: square, triangle, sawtooth or sine
+    #[arg(long, default_value = "square")]
+    waveform: Waveform,
+    /// Start muted, M toggles the sound
+    #[arg(long)]
+    mute: bool,
+    /// Write the sound to a WAV file instead of playing it
+    #[arg(long)]
+    wav: Option<PathBuf>,
+}
+
+// How the screen looks in screenshots and recordings as well as on screen
+#[derive(Args)]
+struct DisplayOptions {
+    /// Colour palette: mono, paper, green, amber, lcd, octo or high-contrast. F7 cycles through them
+    #[arg(long)]
+    palette: Option<String>,
+    /// Custom hex colours instead of a palette: off,on or all four XO-CHIP colours
+    #[arg(long, value_delimiter = ',')]
+    colours: Option<Vec<String>>,
+    /// Flicker reduction: off, vblank, or (the last two frames) or phosphor (lit pixels fade out)
+    #[arg(long)]
+    flicker: Option<FlickerMode>,
+    /// Where screenshots go, F12 takes one. Defaults to the current directory
+    #[arg(long)]
+    screenshot_dir: Option<PathBuf>,
+    /// Screenshot pixel size, 1 for the native 64x32. Defaults to the size on screen
+    #[arg(long)]
+    screenshot_scale: Option<usize>,
+    /// Record from the start: a .gif file, or a directory of frames and audio.wav. F8 starts and stops recordings
+    #[arg(long)]
+    record: Option<PathBuf>,
+    /// Recording format: gif, or png or ppm frames. Defaults to gif for a .gif path and png otherwise
+    #[arg(long)]
+    record_format: Option<RecordFormat>,
+    /// Recording pixel size
+    #[arg(long, default_value_t = 4)]
+    record_scale: usize,
+}
+
+#[derive(Args)]
+struct RunOptions {
+    /// Path to the rom
+    rom: PathBuf,
+    #[command(flatten)]
+    machine: MachineOptions,
+    #[command(flatten)]
+    sound: SoundOptions,
+    #[command(flatten)]
+    display: DisplayOptions,
+    /// Draw in the terminal instead of a window
+    #[arg(long)]
+    terminal: bool,
+    /// Use braille characters in the terminal, 2x4 pixels each instead of 1x2
+    #[arg(long)]
+    braille: bool,
+    /// Size of a pixel when the window opens
+    #[arg(long, default_value_t = 7.0)]
+    scale: f64,
+    /// How the screen grows with the window: integer or fit
+    #[arg(long, default_value = "integer")]
+    scaling: Scaling,
+    /// Use the COSMAC VIP's tall pixels, a 4:3 picture instead of 2:1
+    #[arg(long)]
+    vip_aspect: bool,
+    /// Start in fullscreen, F11 toggles it
+    #[arg(long)]
+    fullscreen: bool,
+    /// Keymap preset: qwerty, azerty, dvorak, or scancode to bind by position
+    #[arg(long)]
+    keymap: Option<String>,
+    /// Bind a key to a hex key, e.g. --bind 4=left --bind 6=right. Scancodes are written scancode:N
+    #[arg(long, value_name = "HEX=KEY")]
+    bind: Vec<String>,
+    /// Bind a game controller button to a hex key, e.g. --pad-bind 5=b (needs the sdl feature)
+    #[arg(long, value_name = "HEX=BUTTON")]
+    pad_bind: Vec<String>,
+    /// How far a stick has to be pushed to press a key, between 0 and 1
+    #[arg(long)]
+    pad_threshold: Option<f32>,
+    /// Show the speed, rom and platform over the game, F9 toggles it
+    #[arg(long)]
+    osd: bool,
+}
 
-type Colour = [f32; 4];
-const PIXEL_SIZE: f64 = 7.0;
-const WHITE: Colour = [1.0; 4];
-const BLACK: Colour = [0.0, 0.0, 0.0, 1.0];
-const UPDATE_RATE: u64 = 500; // I need 500hz for the CPU
-const FPS: u64 = 60; 
-fn read_rom(path: &str) -> Vec<u8> {
-    let mut f = File::open(path).expect("Failed to open rom!");
-    let mut buffer: Vec<u8> = vec![];
-    f.read_to_end(&mut buffer).expect("Failed to read rom!");
-    buffer
-}
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        println!("Usage: cargo run /path/to/rom");
-        return ();
-    }
-    let rom: Vec<u8> = read_rom(&args[1]);
-    let mut cpu = Cpu::new();
-    cpu.load_rom(rom);
-    start_game(cpu);
-}
-fn start_game(mut cpu: cpu::Cpu) {
-    // Initialize settings
-    let w_width: f64 = CHIP_8_WIDTH as f64 * PIXEL_SIZE;
-    let w_height: f64 = CHIP_8_HEIGHT as f64 * PIXEL_SIZE;
-    let settings = WindowSettings::new("Chip 8 Emulator", (w_width, w_height)).exit_on_esc(true);
-    // Create window
-    let mut window: GlutinWindow = settings.build().expect("Could not create window");
-    // Create the event
-    let mut event_settings = EventSettings::new();
-    event_settings.ups = UPDATE_RATE; // 500
-    event_settings.max_fps = FPS; // 60
-    let mut events = Events::new(event_settings);
-    // Initialize OpenGL
-    let opengl = OpenGL::V3_2;
-    let mut gl = GlGraphics::new(opengl);
-    while let Some(e) = events.next(&mut window) {
-        if let Some(_) = e.update_args() { // Every update equals one cpu cycle
-            cpu.emulate_cycle().unwrap();
+#[derive(Args)]
+struct HeadlessOptions {
+    /// Path to the rom
+    rom: PathBuf,
+    #[command(flatten)]
+    machine: MachineOptions,
+    #[command(flatten)]
+    sound: SoundOptions,
+    #[command(flatten)]
+    display: DisplayOptions,
+    /// Number of 60 Hz frames to emulate
+    #[arg(long, default_value_t = 600)]
+    frames: u64,
+    /// Take a screenshot at the end
+    #[arg(long)]
+    screenshot: bool,
+}
+
+#[derive(Args)]
+struct InfoOptions {
+    /// Path to the rom
+    rom: PathBuf,
+    #[command(flatten)]
+    machine: MachineOptions,
+}
+
+#[derive(Args)]
+struct TestOptions {
+    /// Path to the rom
+    rom: PathBuf,
+    #[command(flatten)]
+    machine: MachineOptions,
+    /// Number of 60 Hz frames to emulate
+    #[arg(long, default_value_t = 600)]
+    frames: u64,
+    /// A screen to compare with, as printed by this command. A different screen is a failure
+    #[arg(long)]
+    expect: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct BenchOptions {
+    /// Roms to measure besides the synthetic workloads, the bundled roms/ by default
+    roms: Vec<PathBuf>,
+    /// Cycles to run each workload for
+    #[arg(long, default_value_t = 10_000_000)]
+    cycles: u64,
+    /// Instructions per 60 Hz frame. The recompiler's blocks have to fit in a frame, so it's high
+    #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(u32).range(1..))]
+    speed: u32,
+    /// Only measure this engine
+    #[arg(long)]
+    engine: Option<Engine>,
+}
+
+// A rom loaded into a driver, with its settings from the config file and the database
+struct Machine {
+    driver: Driver,
+    config: Config,
+    rom: RomConfig,
+    known: Option<KnownRom>,
+}
+
+fn main() -> ExitCode {
+    // `chip8 [OPTIONS] ROM` is short for `chip8 run [OPTIONS] ROM`
+    let mut args: Vec<OsString> = env::args_os().collect();
+    if let Some(first) = args.get(1).and_then(|a| a.to_str()) {
+        let cli = Cli::command();
+        let known = cli.get_subcommands().any(|c| c.get_name() == first);
+        if !known && !["help", "-h", "--help"].contains(&first) {
+            args.insert(1, "run".into());
         }
-        // Capture a keypress and send it to the CPU
-        if let Some(b) = e.button_args() {
-            if b.state == piston::ButtonState::Release {
-                if let Some(scancode) = b.scancode {
-                    cpu.key_released(scancode);
-                }
+    }
+    let command = Cli::parse_from(args).command;
+    let result = match command {
+        Command::Run(options) => run(&options),
+        Command::Headless(options) => headless(&options),
+        Command::Disasm { rom, output } => disassemble(&rom, output.as_ref()),
+        Command::Asm { source, output } => assemble(&source, output.as_ref()),
+        Command::Info(options) => info(&options),
+        Command::Test(options) => test(&options),
+        Command::Bench(options) => bench(&options),
+        Command::Analyze { rom, dot } => analyze(&rom, dot.as_ref()),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+fn run(options: &RunOptions) -> Result<(), String> {
+    #[cfg(feature = "sdl")]
+    let sdl = sdl2::init().map_err(|e| eprintln!("No SDL: {}", e)).ok();
+    let sink = open_audio(
+        &options.sound,
+        true,
+        #[cfg(feature = "sdl")]
+        sdl.as_ref(),
+    )?;
+    let Machine { mut driver, config, rom, known } = load_machine(&options.rom, &options.machine, sink)?;
+    report_detection(&driver, known.as_ref());
+    // A new game every time, unless the seed is given
+    let clock = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+    driver.cpu.seed(options.machine.seed.unwrap_or(clock));
+    driver.reset();
+    let known = known.unwrap_or_default();
+    let mut palettes = PaletteCycle::new(choose_palette(&options.display, &config, &rom, &known)?);
+    driver.flicker = Flicker::new(choose_flicker(&options.display, &config, &rom)?);
+    let keymap = choose_keymap(options, &config, &rom, &known)?;
+    #[cfg(feature = "sdl")]
+    if let Some(sdl) = &sdl {
+        let (keymap, threshold) = choose_pad_keymap(options, &config, &rom, &known)?;
+        match chip8::gamepad::SdlGamepads::open(sdl, keymap, threshold) {
+            Ok(gamepads) => driver.inputs.push(Box::new(gamepads)),
+            Err(e) => eprintln!("No game controllers: {}", e),
+        }
+    }
+    let mut osd = Osd::new(options.osd);
+    let (screenshots, recordings) = capture_settings(&options.display, &options.sound, &config);
+    start_recording(&mut driver, &options.display, &palettes, &recordings)?;
+    if options.terminal {
+        let glyphs = if options.braille { Glyphs::Braille } else { Glyphs::HalfBlock };
+        terminal::start_terminal(
+            &mut driver,
+            glyphs,
+            &mut palettes,
+            &mut osd,
+            &keymap,
+            &screenshots,
+            &recordings,
+        )
+        .map_err(|e| format!("Terminal error: {}", e))?;
+    } else {
+        let window_options = WindowOptions {
+            scale: options.scale,
+            scaling: options.scaling,
+            vip_aspect: options.vip_aspect,
+            fullscreen: options.fullscreen,
+        };
+        let result = window::start_window(
+            &mut driver,
+            &mut palettes,
+            &mut osd,
+            &keymap,
+            &screenshots,
+            &recordings,
+            &window_options,
+        );
+        if let Err(e) = result {
+            return finish(driver).and(Err(format!("{}. Without OpenGL, --terminal plays in the terminal", e)));
+        }
+    }
+    // Quitting from the crash screen is a failure too
+    let crash = driver.crash.take();
+    finish(driver)?;
+    match crash {
+        Some(crash) => Err(crash.to_string()),
+        None => Ok(()),
+    }
+}
+fn headless(options: &HeadlessOptions) -> Result<(), String> {
+    let sink = open_audio(
+        &options.sound,
+        false,
+        #[cfg(feature = "sdl")]
+        None,
+    )?;
+    let Machine { mut driver, config, rom, known } = load_machine(&options.rom, &options.machine, sink)?;
+    report_detection(&driver, known.as_ref());
+    let known = known.unwrap_or_default();
+    let palettes = PaletteCycle::new(choose_palette(&options.display, &config, &rom, &known)?);
+    driver.flicker = Flicker::new(choose_flicker(&options.display, &config, &rom)?);
+    let (screenshots, recordings) = capture_settings(&options.display, &options.sound, &config);
+    start_recording(&mut driver, &options.display, &palettes, &recordings)?;
+    let result = run_frames(&mut driver, options.frames);
+    if result.is_ok() && options.screenshot {
+        let path = screenshots
+            .save(&driver, palettes.current(), (1, 1))
+            .map_err(|e| format!("Failed to save screenshot: {}", e))?;
+        println!("{}", path.display());
+    }
+    // What was recorded up to an error is still worth keeping
+    finish(driver).and(result)
+}
+fn disassemble(rom: &Path, output: Option<&PathBuf>) -> Result<(), String> {
+    let rom = fs::read(rom).map_err(|e| format!("Failed to read {}: {}", rom.display(), e))?;
+    let listing = disasm::listing(&rom);
+    match output {
+        Some(path) => fs::write(path, listing).map_err(|e| format!("Failed to write {}: {}", path.display(), e)),
+        None => {
+            print!("{}", listing);
+            Ok(())
+        }
+    }
+}
+fn assemble(source: &Path, output: Option<&PathBuf>) -> Result<(), String> {
+    let text = fs::read_to_string(source).map_err(|e| format!("Failed to read {}: {}", source.display(), e))?;
+    let rom = asm::assemble(&text).map_err(|e| format!("{}: {}", source.display(), e))?;
+    let path = output.cloned().unwrap_or_else(|| source.with_extension("ch8"));
+    fs::write(&path, rom).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+fn info(options: &InfoOptions) -> Result<(), String> {
+    let Machine { driver, rom, known, .. } = load_machine(&options.rom, &options.machine, Box::new(NullSink::default()))?;
+    println!("File      {}", options.rom.display());
+    println!("Size      {} bytes", fs::metadata(&options.rom).map_or(0, |m| m.len()));
+    println!("SHA-1     {}", driver.rom_sha1());
+    match &known {
+        Some(known) => {
+            println!("Title     {}", known.title);
+            if !known.authors.is_empty() {
+                println!("Authors   {}", known.authors.join(", "));
             }
-            if b.state == piston::ButtonState::Press {
-                if let Some(scancode) = b.scancode {
-                    cpu.key_pressed(scancode);
-                }
+            let keys: Vec<String> = known.keys.iter().map(|(action, hex)| format!("{} = {:X}", action, hex)).collect();
+            if !keys.is_empty() {
+                println!("Keys      {}", keys.join(", "));
             }
         }
-        if let Some(r) = e.render_args() {
-            gl.draw(r.viewport(), |c, g| {
-                graphics::clear(BLACK, g);
-                // Draw all the pixels
-                for x in 0..CHIP_8_WIDTH {
-                    for y in 0..CHIP_8_HEIGHT {
-                        let pos: [f64; 4] = [
-                            PIXEL_SIZE * x as f64,
-                            PIXEL_SIZE * y as f64,
-                            PIXEL_SIZE * (x + 1) as f64,
-                            PIXEL_SIZE * (y + 1) as f64,
-                        ];
-                        let colour;
-                        if cpu.display[(x, y)] {
-                            colour = BLACK;
-                        } else {
-                            colour = WHITE;
-                        }
-                        graphics::Rectangle::new(colour).draw(pos, &c.draw_state, c.transform, g);
-                    }
-                }
-            });
+        None => println!("Title     unknown"),
+    }
+    if let Some(title) = &rom.title {
+        println!("Config    {}", title);
+    }
+    let cpu = &driver.cpu;
+    println!("Platform  {}", cpu.platform.name());
+    println!("Quirks    {}", cpu.quirks.name().unwrap_or("custom"));
+    println!("Speed     {} instructions per frame", cpu.speed);
+    Ok(())
+}
+fn bench(options: &BenchOptions) -> Result<(), String> {
+    let mut workloads: Vec<(String, Vec<u8>)> =
+        bench::synthetic().into_iter().map(|(name, rom)| (name.to_string(), rom)).collect();
+    let roms = if options.roms.is_empty() { bundled_roms() } else { options.roms.clone() };
+    for path in &roms {
+        let rom = fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let name = path.file_stem().map_or_else(|| path.display().to_string(), |s| s.to_string_lossy().into_owned());
+        workloads.push((name, rom));
+    }
+    let engines = match options.engine {
+        Some(engine) => vec![engine],
+        None => vec![Engine::Interpreter, Engine::Cached, Engine::Recompiler],
+    };
+    let width = workloads.iter().map(|(name, _)| name.len()).max().unwrap_or(0).max("Workload".len());
+    print!("{:<width$}", "Workload");
+    for engine in &engines {
+        print!("  {:>12}", engine.name());
+    }
+    println!();
+    for (name, rom) in &workloads {
+        print!("{:<width$}", name);
+        for &engine in &engines {
+            let measurement = bench::measure(rom, engine, options.speed, options.cycles)
+                .map_err(|e| format!("{} crashed: {}", name, e))?;
+            print!("  {:>8.1} M/s", measurement.per_second() / 1e6);
         }
+        println!();
+    }
+    Ok(())
+}
+fn analyze(rom: &Path, dot: Option<&PathBuf>) -> Result<(), String> {
+    let analysis = analyze::analyze(&fs::read(rom).map_err(|e| format!("Failed to read {}: {}", rom.display(), e))?);
+    print!("{}", analysis);
+    if let Some(path) = dot {
+        fs::write(path, analysis.to_dot()).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    }
+    Ok(())
+}
+// The roms that come with the source, none if it isn't around
+fn bundled_roms() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("roms");
+    let mut roms: Vec<PathBuf> = fs::read_dir(dir)
+        .map(|entries| entries.filter_map(|e| e.ok().map(|e| e.path())).collect())
+        .unwrap_or_default();
+    roms.retain(|path| path.extension().is_some_and(|e| e == "ch8"));
+    roms.sort();
+    roms
+}
+fn test(options: &TestOptions) -> Result<(), String> {
+    let Machine { mut driver, .. } = load_machine(&options.rom, &options.machine, Box::new(NullSink::default()))?;
+    let result = run_frames(&mut driver, options.frames);
+    let screen = capture::text(&driver);
+    finish(driver).and(result)?;
+    let Some(path) = &options.expect else {
+        print!("{}", screen);
+        return Ok(());
+    };
+    let expected = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    if expected != screen {
+        print!("{}", screen);
+        return Err(format!("The screen differs from {}", path.display()));
+    }
+    Ok(())
+}
+// Load the rom and set the CPU up from the command line, the config file and the database
+fn load_machine(path: &Path, options: &MachineOptions, sink: Box<dyn AudioSink>) -> Result<Machine, String> {
+    let config = load_config(options)?;
+    let mut driver = Driver::new(Cpu::new(), sink);
+    driver.load_file(path)?;
+    // Settings the config file keeps for this rom, and what the built-in database knows about it
+    let sha1 = driver.rom_sha1();
+    let rom = config.rom(&sha1, driver.rom_name().as_deref());
+    let known = database::lookup(&sha1);
+    configure_cpu(&mut driver.cpu, options, &rom, &known.clone().unwrap_or_default())?;
+    if let Some(seed) = options.seed {
+        driver.cpu.seed(seed);
+    }
+    if let Some(path) = &options.trace {
+        let trace = Trace::create(path).map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+        driver.trace = Some(trace);
+    }
+    Ok(Machine { driver, config, rom, known })
+}
+fn report_detection(driver: &Driver, known: Option<&KnownRom>) {
+    match known {
+        Some(known) if known.authors.is_empty() => eprintln!("Detected {}", known.title),
+        Some(known) => eprintln!("Detected {} by {}", known.title, known.authors.join(", ")),
+        None => {
+            let sha1 = driver.rom_sha1();
+            eprintln!(
+                "Rom {} isn't in the database. Settings for it go in the config file under [roms.{}]",
+                sha1, sha1
+            );
+        }
+    }
+}
+// Everything that writes files as it goes is finished, so it's complete on disk
+fn finish(mut driver: Driver) -> Result<(), String> {
+    match driver.stop_recording() {
+        Some(Ok(path)) => println!("{}", path.display()),
+        Some(Err(e)) => return Err(format!("Failed to record: {}", e)),
+        None => {}
+    }
+    if let Some(trace) = driver.trace.take() {
+        trace.finish().map_err(|e| format!("Failed to write the trace: {}", e))?;
+    }
+    driver.sink.finish().map_err(|e| format!("Failed to write audio: {}", e))
+}
+fn load_config(options: &MachineOptions) -> Result<Config, String> {
+    // A missing file is only an error if it was asked for explicitly
+    match &options.config {
+        Some(path) => Config::load(path),
+        None => match Config::default_path() {
+            Some(path) if path.exists() => Config::load(&path),
+            _ => Ok(Config::default()),
+        },
+    }
+}
+fn configure_cpu(cpu: &mut Cpu, options: &MachineOptions, rom: &RomConfig, known: &KnownRom) -> Result<(), String> {
+    // The command line, then the rom's settings, then the database
+    let platform = match rom.platform.as_ref().or(known.platform.as_ref()) {
+        Some(name) => name.parse()?,
+        None => Platform::default(),
+    };
+    cpu.platform = options.platform.unwrap_or(platform);
+    cpu.quirks = match options.quirks.as_ref().or(rom.quirks.as_ref()) {
+        Some(name) => Quirks::profile(name)?,
+        None => {
+            let mut quirks = cpu.platform.quirks();
+            // The database's quirks are for the platform it gives, not one picked on the command line
+            if options.platform.is_none() {
+                known.quirks.apply(&mut quirks);
+            }
+            quirks
+        }
+    };
+    cpu.speed = options.speed.or(rom.speed).or(known.speed).unwrap_or(DEFAULT_SPEED);
+    cpu.engine = options.engine;
+    Ok(())
+}
+fn choose_palette(options: &DisplayOptions, config: &Config, rom: &RomConfig, known: &KnownRom) -> Result<Palette, String> {
+    // The command line wins over the rom's settings, then the database's colours, then the
+    // ones for all roms. Custom colours win over a named palette
+    if let Some(colours) = &options.colours {
+        Palette::custom(colours)
+    } else if let Some(name) = &options.palette {
+        Palette::named(name)
+    } else if let Some(colours) = &rom.colours {
+        Palette::custom(colours)
+    } else if let Some(name) = &rom.palette {
+        Palette::named(name)
+    } else if let Some(colours) = &known.colours {
+        Palette::custom(colours)
+    } else if let Some(colours) = &config.display.colours {
+        Palette::custom(colours)
+    } else if let Some(name) = &config.display.palette {
+        Palette::named(name)
+    } else {
+        Ok(Palette::default())
+    }
+}
+fn choose_flicker(options: &DisplayOptions, config: &Config, rom: &RomConfig) -> Result<FlickerMode, String> {
+    // The command line, then the rom's own settings, then the ones for all roms
+    if let Some(mode) = options.flicker {
+        return Ok(mode);
+    }
+    match rom.flicker.as_ref().or(config.display.flicker.as_ref()) {
+        Some(mode) => mode.parse(),
+        None => Ok(FlickerMode::Off),
+    }
+}
+fn choose_keymap(options: &RunOptions, config: &Config, rom: &RomConfig, known: &KnownRom) -> Result<Keymap, String> {
+    // A preset on the command line replaces the config's keymap, and so does one in the rom's
+    // settings. Otherwise the database's keys and then the rom's go on top of the config's.
+    // --bind goes on top of all
+    let mut keymap = match (&options.keymap, &rom.keymap.preset) {
+        (Some(preset), _) => Keymap::preset(preset),
+        (None, Some(_)) => Keymap::from_config(&rom.keymap),
+        (None, None) => Keymap::from_config(&config.keymap).and_then(|mut keymap| {
+            add_known_keys(&mut keymap, known.keyboard_keys());
+            keymap.apply_keys(&rom.keymap.keys)?;
+            Ok(keymap)
+        }),
+    }?;
+    keymap.apply_binds(&options.bind)?;
+    Ok(keymap)
+}
+// The database's keys come on top of the usual ones, so the keypad still works
+fn add_known_keys(keymap: &mut Keymap, keys: std::collections::BTreeMap<u8, Vec<String>>) {
+    for (hex, names) in keys {
+        keymap.add(hex, names.into_iter().map(Binding::Key).collect());
+    }
+}
+#[cfg(feature = "sdl")]
+fn choose_pad_keymap(
+    options: &RunOptions,
+    config: &Config,
+    rom: &RomConfig,
+    known: &KnownRom,
+) -> Result<(Keymap, f32), String> {
+    // The database's buttons, then the config's for all roms, the rom's and --pad-bind on top
+    let mut keymap = Keymap::gamepad();
+    add_known_keys(&mut keymap, known.gamepad_keys());
+    keymap.apply_keys(&config.gamepad.keys)?;
+    keymap.apply_keys(&rom.gamepad.keys)?;
+    keymap.apply_binds(&options.pad_bind)?;
+    let threshold = options
+        .pad_threshold
+        .or(rom.gamepad.threshold)
+        .or(config.gamepad.threshold)
+        .unwrap_or(chip8::gamepad::DEFAULT_THRESHOLD);
+    Ok((keymap, threshold))
+}
+fn capture_settings(options: &DisplayOptions, sound: &SoundOptions, config: &Config) -> (ScreenshotSettings, RecordSettings) {
+    let screenshots = ScreenshotSettings {
+        dir: options.screenshot_dir.clone().or(config.screenshots.dir.clone()).unwrap_or_default(),
+        scale: options.screenshot_scale.or(config.screenshots.scale),
+    };
+    let recordings = RecordSettings {
+        dir: screenshots.dir.clone(),
+        format: options.record_format.unwrap_or(RecordFormat::Gif),
+        scale: options.record_scale,
+        tone: tone_settings(sound),
+    };
+    (screenshots, recordings)
+}
+fn start_recording(
+    driver: &mut Driver,
+    options: &DisplayOptions,
+    palettes: &PaletteCycle,
+    recordings: &RecordSettings,
+) -> Result<(), String> {
+    let Some(path) = &options.record else {
+        return Ok(());
+    };
+    let format = match options.record_format {
+        Some(format) => format,
+        None if path.extension().is_some_and(|e| e == "gif") => RecordFormat::Gif,
+        None => RecordFormat::Png,
+    };
+    let recorder = Recorder::start(path, format, palettes.current(), options.record_scale, recordings.tone)
+        .map_err(|e| format!("Failed to start recording: {}", e))?;
+    driver.recorder = Some(recorder);
+    Ok(())
+}
+fn run_frames(driver: &mut Driver, frames: u64) -> Result<(), String> {
+    for _ in 0..frames {
+        // The report has more to say than the error
+        driver.run_frame().map_err(|e| driver.crash.as_ref().map_or(e.to_string(), |c| c.to_string()))?;
+    }
+    Ok(())
+}
+fn tone_settings(options: &SoundOptions) -> ToneSettings {
+    ToneSettings {
+        frequency: options.frequency,
+        volume: options.volume,
+        waveform: options.waveform,
+    }
+}
+// `play` is false without a window, when only a WAV file makes sense
+fn open_audio(
+    options: &SoundOptions,
+    play: bool,
+    #[cfg(feature = "sdl")] sdl: Option<&sdl2::Sdl>,
+) -> Result<Box<dyn AudioSink>, String> {
+    let settings = tone_settings(options);
+    let mut sink: Box<dyn AudioSink> = Box::new(NullSink::default());
+    if let Some(path) = &options.wav {
+        let wav = WavSink::create(path, settings).map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+        sink = Box::new(wav);
+    } else if play {
+        #[cfg(feature = "sdl")]
+        if let Some(sdl) = sdl {
+            match chip8::audio::SdlSink::open(sdl, settings) {
+                Ok(speakers) => sink = Box::new(speakers),
+                Err(e) => eprintln!("No sound: {}", e),
+            }
+        }
+    }
+    sink.set_muted(options.mute);
+    Ok(sink)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chip8::database::QuirkOverrides;
+    fn machine(args: &[&str]) -> MachineOptions {
+        let args = ["chip8", "info"].iter().chain(args).chain(&["game.ch8"]);
+        match Cli::try_parse_from(args).unwrap().command {
+            Command::Info(options) => options.machine,
+            _ => unreachable!(),
+        }
+    }
+    #[test]
+    fn test_explicit_platform_beats_database_quirks() {
+        let known = KnownRom {
+            platform: Some("chip-8".to_string()),
+            quirks: QuirkOverrides {
+                vblank: Some(true),
+                ..QuirkOverrides::default()
+            },
+            ..KnownRom::default()
+        };
+        let mut cpu = Cpu::new();
+        configure_cpu(&mut cpu, &machine(&[]), &RomConfig::default(), &known).unwrap();
+        assert_eq!(cpu.platform, Platform::Chip8);
+        assert!(cpu.quirks.vblank);
+        configure_cpu(&mut cpu, &machine(&["--platform", "schip"]), &RomConfig::default(), &known).unwrap();
+        assert_eq!(cpu.quirks, Platform::Schip.quirks());
     }
 }
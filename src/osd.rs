@@ -0,0 +1,223 @@
+// On-screen display drawn over the game by the frontends
+//
+// The stats (rom, platform, speed) can be toggled, the indicators for pause,
+// fast-forward, mute and recording and the messages after a hotkey are always shown.
+// The window draws the text with the tiny font below, the terminal has its own.
+use crate::driver::{Driver, FAST_FORWARD_SPEED};
+use std::time::{Duration, Instant};
+
+pub const GLYPH_WIDTH: usize = 3;
+pub const GLYPH_HEIGHT: usize = 5;
+pub const GLYPH_ADVANCE: usize = GLYPH_WIDTH + 1; // a blank column between characters
+const MESSAGE_TIME: Duration = Duration::from_secs(2);
+const SAMPLE_TIME: Duration = Duration::from_secs(1);
+
+// 3x5 glyphs, one row per byte with the leftmost pixel in bit 2. Lowercase is drawn as uppercase
+const FONT: [(char, [u8; 5]); 56] = [
+    ('0', [0b111, 0b101, 0b101, 0b101, 0b111]),
+    ('1', [0b010, 0b110, 0b010, 0b010, 0b111]),
+    ('2', [0b111, 0b001, 0b111, 0b100, 0b111]),
+    ('3', [0b111, 0b001, 0b111, 0b001, 0b111]),
+    ('4', [0b101, 0b101, 0b111, 0b001, 0b001]),
+    ('5', [0b111, 0b100, 0b111, 0b001, 0b111]),
+    ('6', [0b111, 0b100, 0b111, 0b101, 0b111]),
+    ('7', [0b111, 0b001, 0b010, 0b010, 0b010]),
+    ('8', [0b111, 0b101, 0b111, 0b101, 0b111]),
+    ('9', [0b111, 0b101, 0b111, 0b001, 0b111]),
+    ('A', [0b010, 0b101, 0b111, 0b101, 0b101]),
+    ('B', [0b110, 0b101, 0b110, 0b101, 0b110]),
+    ('C', [0b011, 0b100, 0b100, 0b100, 0b011]),
+    ('D', [0b110, 0b101, 0b101, 0b101, 0b110]),
+    ('E', [0b111, 0b100, 0b110, 0b100, 0b111]),
+    ('F', [0b111, 0b100, 0b110, 0b100, 0b100]),
+    ('G', [0b011, 0b100, 0b101, 0b101, 0b011]),
+    ('H', [0b101, 0b101, 0b111, 0b101, 0b101]),
+    ('I', [0b111, 0b010, 0b010, 0b010, 0b111]),
+    ('J', [0b001, 0b001, 0b001, 0b101, 0b010]),
+    ('K', [0b101, 0b101, 0b110, 0b101, 0b101]),
+    ('L', [0b100, 0b100, 0b100, 0b100, 0b111]),
+    ('M', [0b101, 0b111, 0b111, 0b101, 0b101]),
+    ('N', [0b110, 0b101, 0b101, 0b101, 0b101]),
+    ('O', [0b010, 0b101, 0b101, 0b101, 0b010]),
+    ('P', [0b110, 0b101, 0b110, 0b100, 0b100]),
+    ('Q', [0b010, 0b101, 0b101, 0b110, 0b011]),
+    ('R', [0b110, 0b101, 0b110, 0b101, 0b101]),
+    ('S', [0b011, 0b100, 0b010, 0b001, 0b110]),
+    ('T', [0b111, 0b010, 0b010, 0b010, 0b010]),
+    ('U', [0b101, 0b101, 0b101, 0b101, 0b111]),
+    ('V', [0b101, 0b101, 0b101, 0b101, 0b010]),
+    ('W', [0b101, 0b101, 0b111, 0b111, 0b101]),
+    ('X', [0b101, 0b101, 0b010, 0b101, 0b101]),
+    ('Y', [0b101, 0b101, 0b010, 0b010, 0b010]),
+    ('Z', [0b111, 0b001, 0b010, 0b100, 0b111]),
+    (' ', [0b000, 0b000, 0b000, 0b000, 0b000]),
+    ('.', [0b000, 0b000, 0b000, 0b000, 0b010]),
+    (',', [0b000, 0b000, 0b000, 0b010, 0b100]),
+    (':', [0b000, 0b010, 0b000, 0b010, 0b000]),
+    ('\'', [0b010, 0b010, 0b000, 0b000, 0b000]),
+    ('-', [0b000, 0b000, 0b111, 0b000, 0b000]),
+    ('+', [0b000, 0b010, 0b111, 0b010, 0b000]),
+    ('=', [0b000, 0b111, 0b000, 0b111, 0b000]),
+    ('_', [0b000, 0b000, 0b000, 0b000, 0b111]),
+    ('/', [0b001, 0b001, 0b010, 0b100, 0b100]),
+    ('%', [0b101, 0b001, 0b010, 0b100, 0b101]),
+    ('!', [0b010, 0b010, 0b010, 0b000, 0b010]),
+    ('?', [0b111, 0b001, 0b010, 0b000, 0b010]),
+    ('(', [0b001, 0b010, 0b010, 0b010, 0b001]),
+    (')', [0b100, 0b010, 0b010, 0b010, 0b100]),
+    ('[', [0b011, 0b010, 0b010, 0b010, 0b011]),
+    (']', [0b110, 0b010, 0b010, 0b010, 0b110]),
+    ('<', [0b001, 0b010, 0b100, 0b010, 0b001]),
+    ('>', [0b100, 0b010, 0b001, 0b010, 0b100]),
+    ('#', [0b101, 0b111, 0b101, 0b111, 0b101]),
+];
+
+// Characters missing from the font are drawn as '?'
+pub fn glyph(c: char) -> [u8; 5] {
+    let c = c.to_ascii_uppercase();
+    FONT.iter()
+        .find(|(g, _)| *g == c)
+        .or_else(|| FONT.iter().find(|(g, _)| *g == '?'))
+        .map(|(_, rows)| *rows)
+        .unwrap()
+}
+
+// The lit pixels of a line of text, in font pixels from its top left corner
+pub fn text_pixels(text: &str) -> Vec<(usize, usize)> {
+    let mut pixels = vec![];
+    for (idx, c) in text.chars().enumerate() {
+        for (y, row) in glyph(c).iter().enumerate() {
+            for x in 0..GLYPH_WIDTH {
+                if row >> (GLYPH_WIDTH - 1 - x) & 1 == 1 {
+                    pixels.push((idx * GLYPH_ADVANCE + x, y));
+                }
+            }
+        }
+    }
+    pixels
+}
+
+pub fn text_width(text: &str) -> usize {
+    (text.chars().count() * GLYPH_ADVANCE).saturating_sub(1)
+}
+
+pub struct Osd {
+    pub visible: bool,                // whether the stats are shown
+    messages: Vec<(String, Instant)>, // and when they go away
+    // When the rates were last measured, with the frames and instructions run by then
+    sample: Option<(Instant, u64, u64)>,
+    fps: f64,
+    ips: f64,
+}
+
+impl Osd {
+    pub fn new(visible: bool) -> Self {
+        Osd {
+            visible,
+            messages: vec![],
+            sample: None,
+            fps: 0.0,
+            ips: 0.0,
+        }
+    }
+    // Show a message for a couple of seconds, e.g. "Reset"
+    pub fn message(&mut self, text: impl Into<String>, now: Instant) {
+        self.messages.push((text.into(), now + MESSAGE_TIME));
+    }
+    // Called once per drawn frame: measures the speed and drops old messages
+    pub fn update(&mut self, driver: &Driver, now: Instant) {
+        self.messages.retain(|(_, until)| *until > now);
+        let frames = driver.cpu.frames();
+        let instructions = driver.instructions();
+        match self.sample {
+            // A reset starts the frames over, so measure from there
+            Some((_, f, _)) if frames < f => self.sample = Some((now, frames, instructions)),
+            Some((then, f, i)) if now - then >= SAMPLE_TIME => {
+                let secs = (now - then).as_secs_f64();
+                self.fps = (frames - f) as f64 / secs;
+                self.ips = (instructions - i) as f64 / secs;
+                self.sample = Some((now, frames, instructions));
+            }
+            Some(_) => {}
+            None => self.sample = Some((now, frames, instructions)),
+        }
+    }
+    // The lines of text to draw, top to bottom
+    pub fn lines(&self, driver: &Driver) -> Vec<String> {
+        let mut lines = vec![];
+        if self.visible {
+            lines.push(driver.rom_name().unwrap_or_else(|| "No rom".to_string()));
+            let quirks = driver.cpu.quirks.name().unwrap_or("custom");
+            lines.push(format!("{} - {} quirks", driver.cpu.platform.name(), quirks));
+            lines.push(format!("FPS {:.0} IPS {:.0}", self.fps, self.ips));
+        }
+        let mut indicators = vec![];
+        if driver.paused {
+            indicators.push("Paused".to_string());
+        }
+        if driver.fast_forward {
+            indicators.push(format!(">> x{}", FAST_FORWARD_SPEED));
+        }
+        if driver.sink.is_muted() {
+            indicators.push("Muted".to_string());
+        }
+        if driver.recorder.is_some() {
+            indicators.push("Rec".to_string());
+        }
+        if !indicators.is_empty() {
+            lines.push(indicators.join(" "));
+        }
+        lines.extend(self.messages.iter().map(|(text, _)| text.clone()));
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::NullSink;
+    use crate::cpu::Cpu;
+    #[test]
+    fn test_text_pixels() {
+        assert_eq!(glyph('a'), glyph('A'));
+        assert_eq!(glyph('~'), glyph('?'));
+        assert_eq!(text_width("FPS"), 11);
+        // 'I' is a full top row, then a column down the middle
+        let pixels = text_pixels(" I");
+        assert!(pixels.contains(&(4, 0)) && pixels.contains(&(6, 0)));
+        assert!(pixels.contains(&(5, 2)) && !pixels.contains(&(4, 2)));
+        assert_eq!(pixels.len(), 9);
+    }
+    #[test]
+    fn test_osd_lines() {
+        let mut driver = Driver::new(Cpu::new(), Box::new(NullSink::default()));
+        let mut osd = Osd::new(false);
+        let start = Instant::now();
+        assert!(osd.lines(&driver).is_empty());
+        driver.paused = true;
+        driver.sink.set_muted(true);
+        osd.message("Reset", start);
+        osd.update(&driver, start);
+        assert_eq!(osd.lines(&driver), ["Paused Muted", "Reset"]);
+        // The message goes away, the stats come up
+        osd.visible = true;
+        osd.update(&driver, start + MESSAGE_TIME);
+        let lines = osd.lines(&driver);
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], "No rom");
+    }
+    #[test]
+    fn test_osd_rates() {
+        let mut driver = Driver::new(Cpu::new(), Box::new(NullSink::default()));
+        driver.load_rom(vec![0x12, 0x00]).unwrap(); // jump to itself
+        let mut osd = Osd::new(true);
+        let start = Instant::now();
+        osd.update(&driver, start);
+        for _ in 0..60 {
+            driver.run_frame().unwrap();
+        }
+        osd.update(&driver, start + SAMPLE_TIME);
+        assert_eq!(osd.fps, 60.0);
+        assert_eq!(osd.ips, driver.instructions() as f64);
+    }
+}
@@ -0,0 +1,163 @@
+// Colour palettes for the frontends
+//
+// A palette has four colours so XO-CHIP's two bit planes fit in later:
+// 0 is an unlit pixel, 1 is a lit pixel, 2 and 3 are the second plane and
+// both planes overlapping. Plain CHIP-8 only uses the first two.
+pub type Colour = [f32; 4];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Palette {
+    pub name: String,
+    pub colours: [Colour; 4],
+}
+
+// (name, background, foreground, second plane, both planes)
+const BUILTIN: [(&str, [u32; 4]); 7] = [
+    ("mono", [0x000000, 0xFFFFFF, 0xAAAAAA, 0x555555]),
+    ("paper", [0xFFFFFF, 0x000000, 0x555555, 0xAAAAAA]),
+    ("green", [0x0A1A0A, 0x33FF66, 0x1A8033, 0x0F4D1F]),
+    ("amber", [0x1A0F00, 0xFFB000, 0x995C00, 0x4D2E00]),
+    ("lcd", [0x9BBC0F, 0x0F380F, 0x306230, 0x8BAC0F]),
+    ("octo", [0x996600, 0xFFCC00, 0xFF6600, 0x662200]),
+    ("high-contrast", [0x000000, 0xFFFF00, 0x00FFFF, 0xFFFFFF]),
+];
+
+fn rgb(value: u32) -> Colour {
+    [
+        ((value >> 16) & 0xFF) as f32 / 255.0,
+        ((value >> 8) & 0xFF) as f32 / 255.0,
+        (value & 0xFF) as f32 / 255.0,
+        1.0,
+    ]
+}
+
+impl Palette {
+    // All the built-in palettes, the first one is the default
+    pub fn builtin() -> Vec<Palette> {
+        BUILTIN
+            .iter()
+            .map(|(name, colours)| Palette {
+                name: name.to_string(),
+                colours: colours.map(rgb),
+            })
+            .collect()
+    }
+    pub fn named(name: &str) -> Result<Palette, String> {
+        Self::builtin()
+            .into_iter()
+            .find(|p| p.name == name)
+            .ok_or_else(|| format!("Unknown palette: {}", name))
+    }
+    // Builds a palette out of 2 or 4 hex colours, e.g. ["#000000", "#FFFFFF"].
+    // With only two colours the XO-CHIP ones are blended from them.
+    pub fn custom(colours: &[String]) -> Result<Palette, String> {
+        let parsed = colours.iter().map(|c| parse_colour(c)).collect::<Result<Vec<_>, _>>()?;
+        let colours = match parsed[..] {
+            [off, on] => [off, on, blend(off, on, 2.0 / 3.0), blend(off, on, 1.0 / 3.0)],
+            [off, on, plane2, both] => [off, on, plane2, both],
+            _ => return Err(format!("Expected 2 or 4 colours, got {}", parsed.len())),
+        };
+        Ok(Palette {
+            name: "custom".to_string(),
+            colours,
+        })
+    }
+    pub fn background(&self) -> Colour {
+        self.colours[0]
+    }
+    pub fn foreground(&self) -> Colour {
+        self.colours[1]
+    }
+    // Between the background (0.0) and the foreground (1.0), for fading pixels
+    pub fn shade(&self, brightness: f32) -> Colour {
+        blend(self.background(), self.foreground(), brightness)
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self::builtin().remove(0)
+    }
+}
+
+// The palettes a hotkey cycles through
+pub struct PaletteCycle {
+    palettes: Vec<Palette>,
+    current: usize,
+}
+
+impl PaletteCycle {
+    // Cycles through the built-in palettes, starting at `palette`
+    pub fn new(palette: Palette) -> Self {
+        let mut palettes = Palette::builtin();
+        let current = match palettes.iter().position(|p| *p == palette) {
+            Some(idx) => idx,
+            None => {
+                palettes.insert(0, palette);
+                0
+            }
+        };
+        PaletteCycle { palettes, current }
+    }
+    pub fn current(&self) -> &Palette {
+        &self.palettes[self.current]
+    }
+    pub fn advance(&mut self) -> &Palette {
+        self.current = (self.current + 1) % self.palettes.len();
+        self.current()
+    }
+}
+
+fn blend(a: Colour, b: Colour, t: f32) -> Colour {
+    [
+        a[0] + (b[0] - a[0]) * t,
+        a[1] + (b[1] - a[1]) * t,
+        a[2] + (b[2] - a[2]) * t,
+        1.0,
+    ]
+}
+
+// Parses "#RRGGBB" or "RRGGBB"
+pub fn parse_colour(s: &str) -> Result<Colour, String> {
+    let hex = s.trim().trim_start_matches('#');
+    if hex.len() != 6 {
+        return Err(format!("Invalid colour: {}", s));
+    }
+    u32::from_str_radix(hex, 16)
+        .map(rgb)
+        .map_err(|_| format!("Invalid colour: {}", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_parse_colour() {
+        assert_eq!(parse_colour("#FF8000"), Ok([1.0, 128.0 / 255.0, 0.0, 1.0]));
+        assert_eq!(parse_colour("000000"), Ok([0.0, 0.0, 0.0, 1.0]));
+        assert!(parse_colour("#FFF").is_err());
+        assert!(parse_colour("#GGGGGG").is_err());
+    }
+    #[test]
+    fn test_custom_palette() {
+        let palette = Palette::custom(&["#000000".to_string(), "#FFFFFF".to_string()]).unwrap();
+        assert_eq!(palette.background(), [0.0, 0.0, 0.0, 1.0]);
+        assert_eq!(palette.foreground(), [1.0, 1.0, 1.0, 1.0]);
+        assert!(Palette::custom(&["#000000".to_string()]).is_err());
+    }
+    #[test]
+    fn test_named_palette() {
+        assert_eq!(Palette::named("amber").unwrap().name, "amber");
+        assert!(Palette::named("purple").is_err());
+        assert_eq!(Palette::default().name, "mono");
+    }
+    #[test]
+    fn test_palette_cycle() {
+        let mut cycle = PaletteCycle::new(Palette::named("high-contrast").unwrap());
+        assert_eq!(cycle.advance().name, "mono");
+        let custom = Palette::custom(&["#123456".to_string(), "#654321".to_string()]).unwrap();
+        let mut cycle = PaletteCycle::new(custom);
+        assert_eq!(cycle.current().name, "custom");
+        assert_eq!(cycle.advance().name, "mono");
+    }
+}
@@ -0,0 +1,131 @@
+// A basic-block recompiler: straight-line code runs as one chain of closures
+//
+// A block is the run of instructions from an address up to the first one that
+// branches, skips, draws, waits for a key, touches memory or can fail. Those are
+// left to the interpreter, so a block always runs to its end. On the way the
+// register effects are folded: loads and adds of constants into the same
+// register, and loads of I, become one step each. A block only runs when it ends
+// before the timers next tick, so it does exactly what that many cycles of the
+// interpreter would.
+use super::{decode, Cpu, Decoded, ADDRESS_MASK, RAM_SIZE};
+
+// The longest block, in instructions
+pub(super) const MAX_BLOCK: usize = 32;
+
+type Step = Box<dyn Fn(&mut Cpu) + Send + Sync>;
+
+pub(super) struct Block {
+    steps: Vec<Step>,
+    pub(super) instructions: Vec<(u16, u16)>, // address and opcode of each instruction it stands for
+    end: u16,                                  // where the PC goes afterwards
+}
+
+impl Block {
+    pub(super) fn run(&self, cpu: &mut Cpu) {
+        for step in &self.steps {
+            step(cpu);
+        }
+        cpu.pc = self.end;
+    }
+}
+
+// What a block does to the registers, before it's turned into closures
+#[derive(Clone, Copy)]
+enum Effect {
+    Load(usize, u8), // Vx = byte
+    Add(usize, u8),  // Vx += byte, which leaves VF alone
+    LoadI(u16),
+    Run(Decoded), // anything else, by its handler
+}
+
+// Whether an instruction can go in a block: it doesn't change the flow, touch the memory,
+// the screen or the keys, and can't fail
+fn is_straight(opcode: u16) -> bool {
+    match (opcode >> 12, opcode & 0xF) {
+        (0x6 | 0x7 | 0xA | 0xC, _) => true,
+        (0x8, n) => matches!(n, 0x0..=0x7 | 0xE),
+        (0xF, _) => matches!(opcode & 0xFF, 0x07 | 0x15 | 0x18 | 0x1E | 0x29),
+        _ => false,
+    }
+}
+
+// The block starting at an address, None when it would be less than two instructions long
+pub(super) fn compile(ram: &[u8; RAM_SIZE], start: usize) -> Option<Block> {
+    let mut effects = vec![];
+    let mut instructions = vec![];
+    let mut address = start;
+    while instructions.len() < MAX_BLOCK && address + 1 < RAM_SIZE {
+        let opcode = u16::from_be_bytes([ram[address], ram[address + 1]]);
+        if !is_straight(opcode) {
+            break;
+        }
+        instructions.push((address as u16, opcode));
+        fold(&mut effects, opcode);
+        address += 2;
+    }
+    if instructions.len() < 2 {
+        return None;
+    }
+    Some(Block {
+        steps: effects.into_iter().map(step).collect(),
+        instructions,
+        end: address as u16 & ADDRESS_MASK,
+    })
+}
+
+// Add an instruction's effect, merged into the one before when they work on the same register
+fn fold(effects: &mut Vec<Effect>, opcode: u16) {
+    let x = ((opcode >> 8) & 0xF) as usize;
+    let nn = opcode as u8;
+    let effect = match opcode >> 12 {
+        0x6 => Effect::Load(x, nn),
+        0x7 => Effect::Add(x, nn),
+        0xA => Effect::LoadI(opcode & 0xFFF),
+        _ => Effect::Run(decode(opcode)),
+    };
+    let folded = match (effects.last().copied(), effect) {
+        (Some(Effect::Load(last, a)), Effect::Add(x, b)) if last == x => Effect::Load(x, a.wrapping_add(b)),
+        (Some(Effect::Add(last, a)), Effect::Add(x, b)) if last == x => Effect::Add(x, a.wrapping_add(b)),
+        (Some(Effect::Load(last, _) | Effect::Add(last, _)), Effect::Load(x, b)) if last == x => Effect::Load(x, b),
+        (Some(Effect::LoadI(_)), Effect::LoadI(address)) => Effect::LoadI(address),
+        _ => {
+            effects.push(effect);
+            return;
+        }
+    };
+    *effects.last_mut().unwrap() = folded;
+}
+
+fn step(effect: Effect) -> Step {
+    match effect {
+        Effect::Load(x, byte) => Box::new(move |cpu| cpu.v[x] = byte),
+        Effect::Add(x, byte) => Box::new(move |cpu| cpu.v[x] = cpu.v[x].wrapping_add(byte)),
+        Effect::LoadI(address) => Box::new(move |cpu| cpu.i = address as usize),
+        // The handlers of straight-line instructions always succeed
+        Effect::Run(decoded) => Box::new(move |cpu| {
+            let _ = (decoded.execute)(cpu, &decoded);
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_blocks_end_before_branches() {
+        let mut cpu = Cpu::new();
+        // LD V0, 1; ADD V0, 2; ADD V0, 3; LD I, 0x300; LD I, 0x304; SHR V1; SE V0, 6
+        cpu.load_rom(vec![0x60, 0x01, 0x70, 0x02, 0x70, 0x03, 0xA3, 0x00, 0xA3, 0x04, 0x81, 0x16, 0x30, 0x06])
+            .unwrap();
+        let block = compile(&cpu.ram, 0x200).unwrap();
+        assert_eq!(block.instructions.len(), 6);
+        assert_eq!(block.instructions[5], (0x20A, 0x8116));
+        // The loads and adds folded into one step each
+        assert_eq!(block.steps.len(), 3);
+        block.run(&mut cpu);
+        assert_eq!((cpu.v[0], cpu.i, cpu.pc), (6, 0x304, 0x20C));
+        // Too short to be worth it
+        assert!(compile(&cpu.ram, 0x20A).is_none());
+        assert!(compile(&cpu.ram, 0x20C).is_none());
+    }
+}
@@ -0,0 +1,265 @@
+// Recording every emulated frame, for demo clips and bug reports
+//
+// A GIF is one file. The PNG and PPM formats write a directory of numbered
+// frames with the sound next to them as audio.wav, ready for ffmpeg:
+//
+//     ffmpeg -framerate 60 -i frame-%06d.png -i audio.wav clip.mp4
+use crate::audio::{AudioSink, ToneSettings, WavSink};
+use crate::capture::{self, timestamped_path, Image};
+use crate::cpu::{CHIP_8_HEIGHT, CHIP_8_WIDTH};
+use crate::driver::Driver;
+use crate::palette::Palette;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// GIFs store shades of the palette, so fading phosphor pixels survive
+const GIF_SHADES: usize = 16;
+// GIF delays are in 1/100 s, and players slow anything under 2 down
+const GIF_MIN_DELAY: u64 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordFormat {
+    Gif,
+    Png,
+    Ppm,
+}
+
+impl FromStr for RecordFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gif" => Ok(RecordFormat::Gif),
+            "png" => Ok(RecordFormat::Png),
+            "ppm" => Ok(RecordFormat::Ppm),
+            _ => Err(format!("Unknown recording format: {}", s)),
+        }
+    }
+}
+
+enum Output {
+    Gif {
+        encoder: gif::Encoder<BufWriter<File>>,
+        size: (u16, u16), // width and height of every frame
+        // The frame waiting for its delay: shades, palette, and the frame it started at
+        pending: Option<(Vec<u8>, Vec<u8>, u64)>,
+    },
+    Sequence {
+        format: RecordFormat,
+        audio: WavSink,
+    },
+}
+
+pub struct Recorder {
+    path: PathBuf, // the GIF, or the directory of frames
+    output: Output,
+    palette: Palette,
+    scale: usize,
+    frames: u64,
+    error: Option<io::Error>, // the first write that failed, recording stops there
+}
+
+impl Recorder {
+    pub fn start(
+        path: &Path,
+        format: RecordFormat,
+        palette: &Palette,
+        scale: usize,
+        tone: ToneSettings,
+    ) -> io::Result<Recorder> {
+        let scale = scale.max(1);
+        let output = match format {
+            RecordFormat::Gif => {
+                // A GIF is at most 65535 pixels a side
+                let side = |pixels: usize| pixels.checked_mul(scale).and_then(|side| u16::try_from(side).ok());
+                let Some(size) = side(CHIP_8_WIDTH).zip(side(CHIP_8_HEIGHT)) else {
+                    let error = format!("A scale of {} is too big for a GIF", scale);
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, error));
+                };
+                let file = BufWriter::new(File::create(path)?);
+                let mut encoder = gif::Encoder::new(file, size.0, size.1, &[]).map_err(io::Error::other)?;
+                encoder.set_repeat(gif::Repeat::Infinite).map_err(io::Error::other)?;
+                Output::Gif { encoder, size, pending: None }
+            }
+            RecordFormat::Png | RecordFormat::Ppm => {
+                fs::create_dir_all(path)?;
+                let audio = WavSink::create(&path.join("audio.wav"), tone)?;
+                Output::Sequence { format, audio }
+            }
+        };
+        Ok(Recorder {
+            path: path.to_path_buf(),
+            output,
+            palette: palette.clone(),
+            scale,
+            frames: 0,
+            error: None,
+        })
+    }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    // Frames from now on are in the new colours
+    pub fn set_palette(&mut self, palette: &Palette) {
+        self.palette = palette.clone();
+    }
+    pub fn set_beep(&mut self, on: bool) {
+        if let Output::Sequence { audio, .. } = &mut self.output {
+            audio.set_beep(on);
+        }
+    }
+    // Called by the driver at the end of every emulated frame
+    pub fn frame(&mut self, driver: &Driver) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(e) = self.write_frame(driver) {
+            self.error = Some(e);
+        }
+        self.frames += 1;
+    }
+    fn write_frame(&mut self, driver: &Driver) -> io::Result<()> {
+        match &mut self.output {
+            Output::Gif { encoder, size, pending } => {
+                let shades = gif_shades(driver, self.scale);
+                let palette = gif_palette(&self.palette);
+                match pending.take() {
+                    // Nothing moved, the pending frame just stays up longer
+                    Some((old, old_palette, start)) if old == shades && old_palette == palette => {
+                        *pending = Some((old, old_palette, start));
+                    }
+                    // Too short to show on its own, the new frame takes over its time
+                    Some((_, _, start)) if centiseconds(self.frames) - centiseconds(start) < GIF_MIN_DELAY => {
+                        *pending = Some((shades, palette, start));
+                    }
+                    Some((old, old_palette, start)) => {
+                        let delay = centiseconds(self.frames) - centiseconds(start);
+                        write_gif_frame(encoder, *size, old, old_palette, delay)?;
+                        *pending = Some((shades, palette, self.frames));
+                    }
+                    None => *pending = Some((shades, palette, self.frames)),
+                }
+                Ok(())
+            }
+            Output::Sequence { format, audio } => {
+                audio.end_frame();
+                let image = capture::capture(driver, &self.palette, self.scale, self.scale);
+                let name = format!("frame-{:06}", self.frames + 1);
+                match format {
+                    RecordFormat::Ppm => write_ppm(&image, &self.path.join(name + ".ppm")),
+                    _ => capture::write_png(&image, &self.path.join(name + ".png")),
+                }
+            }
+        }
+    }
+    // Write out whatever is still buffered, the path is where the recording went
+    pub fn finish(self) -> io::Result<PathBuf> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        match self.output {
+            Output::Gif { mut encoder, size, pending } => {
+                if let Some((shades, palette, start)) = pending {
+                    let delay = (centiseconds(self.frames) - centiseconds(start)).max(GIF_MIN_DELAY);
+                    write_gif_frame(&mut encoder, size, shades, palette, delay)?;
+                }
+                encoder.into_inner()?.flush()?;
+            }
+            Output::Sequence { mut audio, .. } => audio.finish()?,
+        }
+        Ok(self.path)
+    }
+}
+
+// How far into the recording a frame starts, in GIF time
+fn centiseconds(frames: u64) -> u64 {
+    (frames * 100 + 30) / 60
+}
+
+fn gif_shades(driver: &Driver, scale: usize) -> Vec<u8> {
+    let mut shades = Vec::with_capacity(CHIP_8_WIDTH * CHIP_8_HEIGHT * scale * scale);
+    for y in 0..CHIP_8_HEIGHT * scale {
+        for x in 0..CHIP_8_WIDTH * scale {
+            let brightness = driver.brightness(x / scale, y / scale);
+            shades.push((brightness * (GIF_SHADES - 1) as f32).round() as u8);
+        }
+    }
+    shades
+}
+
+fn gif_palette(palette: &Palette) -> Vec<u8> {
+    (0..GIF_SHADES)
+        .flat_map(|shade| capture::to_rgb(palette.shade(shade as f32 / (GIF_SHADES - 1) as f32)))
+        .collect()
+}
+
+fn write_gif_frame(
+    encoder: &mut gif::Encoder<BufWriter<File>>,
+    (width, height): (u16, u16),
+    shades: Vec<u8>,
+    palette: Vec<u8>,
+    delay: u64,
+) -> io::Result<()> {
+    let mut frame = gif::Frame::from_palette_pixels(width, height, shades, palette, None);
+    frame.delay = delay.min(u16::MAX as u64) as u16;
+    encoder.write_frame(&frame).map_err(io::Error::other)
+}
+
+pub fn write_ppm(image: &Image, path: &Path) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    write!(file, "P6\n{} {}\n255\n", image.width, image.height)?;
+    file.write_all(&image.to_bytes())?;
+    file.flush()
+}
+
+// What the frontends need to start recordings from a hotkey
+pub struct RecordSettings {
+    pub dir: PathBuf,
+    pub format: RecordFormat,
+    pub scale: usize,
+    pub tone: ToneSettings,
+}
+
+impl RecordSettings {
+    // Start recording into a new timestamped file or directory named after the rom
+    pub fn start(&self, driver: &Driver, palette: &Palette) -> io::Result<Recorder> {
+        fs::create_dir_all(&self.dir)?;
+        let name = driver.rom_name().unwrap_or_else(|| "chip8".to_string());
+        let path = match self.format {
+            RecordFormat::Gif => timestamped_path(&self.dir, &name, "gif"),
+            _ => timestamped_path(&self.dir, &name, ""),
+        };
+        Recorder::start(&path, self.format, palette, self.scale, self.tone)
+    }
+    // The recording hotkey: stop the recording or start one, the result is for the OSD
+    pub fn toggle(&self, driver: &mut Driver, palette: &Palette) -> String {
+        match driver.stop_recording() {
+            Some(Ok(path)) => format!("Saved {}", path.display()),
+            Some(Err(e)) => format!("Recording failed: {}", e),
+            None => match self.start(driver, palette) {
+                Ok(recorder) => {
+                    let message = format!("Recording {}", recorder.path().display());
+                    driver.recorder = Some(recorder);
+                    message
+                }
+                Err(e) => format!("Recording failed: {}", e),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_gif_timing() {
+        assert_eq!(centiseconds(0), 0);
+        assert_eq!(centiseconds(1), 2);
+        assert_eq!(centiseconds(60), 100);
+        let palette = gif_palette(&Palette::default());
+        assert_eq!(palette.len(), GIF_SHADES * 3);
+        assert_eq!(&palette[..3], &[0, 0, 0]);
+        assert_eq!(&palette[palette.len() - 3..], &[255, 255, 255]);
+    }
+}
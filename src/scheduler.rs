@@ -0,0 +1,70 @@
+// When the frontends run the next frame, going by the clock
+//
+// Real time goes into an accumulator and every whole frame's worth that
+// piles up is one frame to run: cpu.speed instructions, one tick of the
+// timers, then the picture. A frontend that falls behind catches up by
+// running several frames at once, but never more than MAX_CATCH_UP, the
+// rest is dropped so a slow machine runs the game slower instead of
+// spending ever longer catching up.
+use std::time::{Duration, Instant};
+
+pub const FRAME_TIME: Duration = Duration::from_nanos(1_000_000_000 / 60);
+// The most frames run at once to catch up
+pub const MAX_CATCH_UP: u32 = 4;
+
+#[derive(Debug)]
+pub struct Scheduler {
+    last: Instant,
+    accumulator: Duration, // time not yet run, less than MAX_CATCH_UP frames
+}
+
+impl Scheduler {
+    pub fn new(now: Instant) -> Self {
+        Scheduler {
+            last: now,
+            accumulator: Duration::ZERO,
+        }
+    }
+    // How many frames to run now, 0 if the next one isn't due yet
+    pub fn frames_due(&mut self, now: Instant) -> u32 {
+        self.accumulator += now.saturating_duration_since(self.last);
+        self.last = now;
+        let due = (self.accumulator.as_nanos() / FRAME_TIME.as_nanos()) as u32;
+        if due > MAX_CATCH_UP {
+            self.accumulator = Duration::ZERO;
+            return MAX_CATCH_UP;
+        }
+        self.accumulator -= FRAME_TIME * due;
+        due
+    }
+    // How long until the next frame is due, for sleeping
+    pub fn until_next(&self, now: Instant) -> Duration {
+        let elapsed = self.accumulator + now.saturating_duration_since(self.last);
+        FRAME_TIME.saturating_sub(elapsed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_frames_accumulate() {
+        let start = Instant::now();
+        let mut scheduler = Scheduler::new(start);
+        assert_eq!(scheduler.frames_due(start + FRAME_TIME / 2), 0);
+        assert_eq!(scheduler.until_next(start + FRAME_TIME / 2), FRAME_TIME - FRAME_TIME / 2);
+        // The half frame left over counts towards the next
+        assert_eq!(scheduler.frames_due(start + FRAME_TIME), 1);
+        assert_eq!(scheduler.frames_due(start + FRAME_TIME * 3), 2);
+        assert_eq!(scheduler.until_next(start + FRAME_TIME * 4), Duration::ZERO);
+    }
+    #[test]
+    fn test_catch_up_is_capped() {
+        let start = Instant::now();
+        let mut scheduler = Scheduler::new(start);
+        assert_eq!(scheduler.frames_due(start + Duration::from_secs(1)), MAX_CATCH_UP);
+        // The rest of the second was dropped
+        assert_eq!(scheduler.frames_due(start + Duration::from_secs(1) + FRAME_TIME / 2), 0);
+        assert_eq!(scheduler.frames_due(start + Duration::from_secs(1) + FRAME_TIME), 1);
+    }
+}
@@ -0,0 +1,300 @@
+// Terminal frontend, for machines without OpenGL (e.g. over SSH)
+//
+// The screen is drawn with half blocks (1x2 pixels per character) or braille
+// (2x4 pixels per character), and the keypad is read from raw-mode stdin.
+use chip8::cpu::{CHIP_8_HEIGHT, CHIP_8_WIDTH};
+use chip8::capture::ScreenshotSettings;
+use chip8::crash::CRASH_KEYS;
+use chip8::driver::{Driver, FAST_FORWARD_SPEED};
+use chip8::keymap::{char_name, Keymap};
+use chip8::osd::Osd;
+use chip8::palette::{Colour, PaletteCycle};
+use chip8::record::RecordSettings;
+use chip8::scheduler::Scheduler;
+use crossterm::style::{Attribute, Color, SetAttribute, SetBackgroundColor, SetForegroundColor};
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::event::{KeyboardEnhancementFlags, PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags};
+use crossterm::{cursor, execute, queue, terminal};
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+// Most terminals only report presses, so a key counts as held for this long
+// after its last press or auto-repeat
+const KEY_HOLD_TIME: Duration = Duration::from_millis(150);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Glyphs {
+    HalfBlock,
+    Braille,
+}
+
+// Puts the terminal into raw mode and restores it when dropped, even on panic
+struct RawTerminal {
+    enhanced: bool, // whether the terminal reports key releases
+}
+
+impl RawTerminal {
+    fn enter() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        let mut stdout = io::stdout();
+        execute!(stdout, terminal::EnterAlternateScreen, cursor::Hide)?;
+        let enhanced = terminal::supports_keyboard_enhancement().unwrap_or(false);
+        if enhanced {
+            execute!(stdout, PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES))?;
+        }
+        Ok(RawTerminal { enhanced })
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        let mut stdout = io::stdout();
+        if self.enhanced {
+            let _ = execute!(stdout, PopKeyboardEnhancementFlags);
+        }
+        let _ = execute!(stdout, cursor::Show, terminal::LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+// The name a key is bound by in the keymap
+fn key_name(code: KeyCode) -> Option<String> {
+    match code {
+        KeyCode::Char(c) => Some(char_name(c)),
+        KeyCode::Up => Some("up".to_string()),
+        KeyCode::Down => Some("down".to_string()),
+        KeyCode::Left => Some("left".to_string()),
+        KeyCode::Right => Some("right".to_string()),
+        KeyCode::Enter => Some("enter".to_string()),
+        KeyCode::Tab => Some("tab".to_string()),
+        KeyCode::Backspace => Some("backspace".to_string()),
+        _ => None,
+    }
+}
+
+pub fn start_terminal(
+    driver: &mut Driver,
+    glyphs: Glyphs,
+    palettes: &mut PaletteCycle,
+    osd: &mut Osd,
+    keymap: &Keymap,
+    screenshots: &ScreenshotSettings,
+    recordings: &RecordSettings,
+) -> io::Result<()> {
+    let raw = RawTerminal::enter()?;
+    let mut stdout = io::stdout();
+    // When each held key should be released, for terminals without release events
+    let mut held: [Option<Instant>; 16] = [None; 16];
+    let mut last_frame = (String::new(), vec![]); // the screen and the OSD
+    let mut crashed = false;
+    let mut scheduler = Scheduler::new(Instant::now());
+    loop {
+        // Handle all pending input
+        while event::poll(Duration::ZERO)? {
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            let quit = key.code == KeyCode::Esc
+                || (key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL));
+            if quit {
+                return Ok(());
+            }
+            // After a crash the keys are for what to do about it
+            if driver.crash.is_some() && key.kind == KeyEventKind::Press {
+                match key.code {
+                    KeyCode::Char('r') => driver.reset(),
+                    KeyCode::Char('c') => driver.resume(),
+                    KeyCode::Char('q') => return Ok(()),
+                    _ => {}
+                }
+                continue;
+            }
+            if key.code == KeyCode::Char('m') && key.kind == KeyEventKind::Press {
+                driver.sink.set_muted(!driver.sink.is_muted());
+            }
+            if key.kind == KeyEventKind::Press {
+                match key.code {
+                    KeyCode::F(1) => driver.set_paused(!driver.paused),
+                    // Errors show up as the crash
+                    KeyCode::F(2) if driver.paused => driver.run_frame().unwrap_or(()),
+                    KeyCode::F(3) if driver.paused => driver.step().unwrap_or(()),
+                    KeyCode::F(4) => {
+                        driver.reset();
+                        osd.message("Reset", Instant::now());
+                    }
+                    KeyCode::F(5) => {
+                        let message = match driver.reload() {
+                            Ok(()) => "Reloaded".to_string(),
+                            Err(e) => e,
+                        };
+                        osd.message(message, Instant::now());
+                    }
+                    KeyCode::F(6) => driver.fast_forward = !driver.fast_forward,
+                    KeyCode::F(7) => {
+                        let palette = palettes.advance();
+                        if let Some(recorder) = &mut driver.recorder {
+                            recorder.set_palette(palette);
+                        }
+                        osd.message(format!("Palette: {}", palette.name), Instant::now());
+                        last_frame.0.clear(); // force a redraw in the new colours
+                    }
+                    KeyCode::F(8) => {
+                        let message = recordings.toggle(driver, palettes.current());
+                        osd.message(message, Instant::now());
+                    }
+                    KeyCode::F(9) => osd.visible = !osd.visible,
+                    // At native size, terminal cells aren't pixels
+                    KeyCode::F(12) => {
+                        let message = match screenshots.save(driver, palettes.current(), (1, 1)) {
+                            Ok(path) => format!("Saved {}", path.display()),
+                            Err(e) => format!("Screenshot failed: {}", e),
+                        };
+                        osd.message(message, Instant::now());
+                    }
+                    _ => {}
+                }
+            }
+            // Terminals don't report scancodes, so only named bindings work here
+            let name = key_name(key.code);
+            if let Some(k) = keymap.lookup(name.as_deref(), None) {
+                match key.kind {
+                    KeyEventKind::Release => {
+                        held[k as usize] = None;
+                        driver.cpu.release_key(k);
+                    }
+                    _ => {
+                        if held[k as usize].is_none() {
+                            driver.cpu.press_key(k);
+                        }
+                        if !raw.enhanced {
+                            held[k as usize] = Some(Instant::now() + KEY_HOLD_TIME);
+                        }
+                    }
+                }
+            }
+        }
+        // Release keys that haven't been repeated for a while
+        let now = Instant::now();
+        for (k, deadline) in held.iter_mut().enumerate() {
+            if deadline.is_some_and(|d| d <= now) {
+                *deadline = None;
+                driver.cpu.release_key(k as u8);
+            }
+        }
+        // Time keeps passing while paused, it just isn't run
+        let due = scheduler.frames_due(now);
+        if !driver.paused {
+            let frames = due * if driver.fast_forward { FAST_FORWARD_SPEED } else { 1 };
+            for _ in 0..frames {
+                // A failed instruction pauses the driver, which then shows the crash
+                if driver.run_frame().is_err() {
+                    break;
+                }
+            }
+        }
+        // Only redraw when something changed
+        osd.update(driver, Instant::now());
+        let screen = render(driver, glyphs);
+        let width = screen.lines().next().map_or(0, |l| l.chars().count());
+        let lines: Vec<String> = match &driver.crash {
+            // Too important to cut off at the width of the screen
+            Some(crash) => crash.lines().into_iter().chain([CRASH_KEYS.to_string()]).collect(),
+            None => osd.lines(driver).iter().map(|l| l.chars().take(width).collect()).collect(),
+        };
+        // The crash report sticks out past the screen, so that is cleared when it comes and goes
+        if driver.crash.is_some() != crashed {
+            crashed = driver.crash.is_some();
+            queue!(stdout, terminal::Clear(terminal::ClearType::All))?;
+            last_frame.0.clear();
+        }
+        // The OSD is part of what's compared, so it gets erased when it goes away
+        let frame = (screen, lines);
+        if frame != last_frame {
+            let palette = palettes.current();
+            queue!(
+                stdout,
+                cursor::MoveTo(0, 0),
+                SetForegroundColor(to_terminal(palette.foreground())),
+                SetBackgroundColor(to_terminal(palette.background()))
+            )?;
+            stdout.write_all(frame.0.as_bytes())?;
+            // The OSD in inverse video over the top rows
+            for (idx, line) in frame.1.iter().enumerate() {
+                queue!(stdout, cursor::MoveTo(0, idx as u16), SetAttribute(Attribute::Reverse))?;
+                stdout.write_all(line.as_bytes())?;
+                queue!(stdout, SetAttribute(Attribute::NoReverse))?;
+            }
+            stdout.flush()?;
+            last_frame = frame;
+        }
+        std::thread::sleep(scheduler.until_next(Instant::now()));
+    }
+}
+
+fn to_terminal(colour: Colour) -> Color {
+    let [r, g, b, _] = colour.map(|c| (c * 255.0).round() as u8);
+    Color::Rgb { r, g, b }
+}
+
+fn render(driver: &Driver, glyphs: Glyphs) -> String {
+    // Only on or off here, so fading phosphor goes off halfway
+    let pixel = |x: usize, y: usize| x < CHIP_8_WIDTH && y < CHIP_8_HEIGHT && driver.brightness(x, y) >= 0.5;
+    let mut frame = String::new();
+    match glyphs {
+        Glyphs::HalfBlock => {
+            for y in (0..CHIP_8_HEIGHT).step_by(2) {
+                for x in 0..CHIP_8_WIDTH {
+                    frame.push(match (pixel(x, y), pixel(x, y + 1)) {
+                        (false, false) => ' ',
+                        (true, false) => '▀',
+                        (false, true) => '▄',
+                        (true, true) => '█',
+                    });
+                }
+                frame.push_str("\r\n");
+            }
+        }
+        Glyphs::Braille => {
+            // Bit of each dot in a braille character, indexed by [y][x]
+            const DOTS: [[u32; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+            for y in (0..CHIP_8_HEIGHT).step_by(4) {
+                for x in (0..CHIP_8_WIDTH).step_by(2) {
+                    let mut bits = 0;
+                    for (dy, row) in DOTS.iter().enumerate() {
+                        for (dx, bit) in row.iter().enumerate() {
+                            if pixel(x + dx, y + dy) {
+                                bits |= bit;
+                            }
+                        }
+                    }
+                    frame.push(char::from_u32(0x2800 + bits).unwrap());
+                }
+                frame.push_str("\r\n");
+            }
+        }
+    }
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chip8::audio::NullSink;
+    use chip8::cpu::Cpu;
+    #[test]
+    fn test_render_fits_screen() {
+        let mut driver = Driver::new(Cpu::new(), Box::new(NullSink::default()));
+        // LD V1, 1; LD I, 0x206; DRW V0, V1, 1 with the sprite 0x80, which lights pixel (0, 1)
+        driver.load_rom(vec![0x61, 0x01, 0xA2, 0x06, 0xD0, 0x11, 0x80]).unwrap();
+        for _ in 0..3 {
+            driver.step().unwrap();
+        }
+        let half = render(&driver, Glyphs::HalfBlock);
+        assert_eq!(half.lines().count(), CHIP_8_HEIGHT / 2);
+        assert!(half.starts_with('▄'));
+        let braille = render(&driver, Glyphs::Braille);
+        assert_eq!(braille.lines().count(), CHIP_8_HEIGHT / 4);
+        assert_eq!(braille.lines().next().unwrap().chars().count(), CHIP_8_WIDTH / 2);
+        assert!(braille.starts_with('\u{2802}'));
+    }
+}
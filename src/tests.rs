@@ -1,238 +1,550 @@
-#[cfg(test)]
-mod tests {
-    use super::*;
-    fn init_cpu() -> Cpu {
-        let mut cpu = Cpu::new();
-        cpu.v = [0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7];
-        cpu
-    }
-    #[test]
-    fn test_initial_state() {
-        let cpu = Cpu::new();
-        assert_eq!(cpu.pc, 0x200);
-        assert_eq!(cpu.sp, 0);
-        assert_eq!(cpu.stack, [0; 16]);
-        // First char in font: 0
-        assert_eq!(cpu.ram[0..5], [0xF0, 0x90, 0x90, 0x90, 0xF0]);
-        // Last char in font: F
-        assert_eq!(
-            cpu.ram[FONTSET.len() - 5..FONTSET.len()],
-            [0xF0, 0x80, 0xF0, 0x80, 0x80]
-        );
-    }
-    #[test]
-    fn test_opcode_annn() {
-        let mut cpu = Cpu::new();
-        // Add opcode 0xA2F0 to ram
-        cpu.ram[0x200] = 0xA2;
-        cpu.ram[0x201] = 0xF0;
-        let initial_pc = cpu.pc;
-        // Check if the operation was successful
-        assert!(cpu.emulate_cycle().is_ok());
-        // Check if the 'I' register has a correct value
-        assert_eq!(cpu.i, 0x02F0);
-        // Check if the program counter was properly incremented
-        assert_eq!(cpu.pc, initial_pc + 2);
-    }
-    #[test]
-    fn test_opcode_annn1() {
-        let mut cpu = init_cpu();
-        cpu.run_opcode(0xA2F0).unwrap();
-        assert_eq!(cpu.i, 0x02F0);
-        assert_eq!(cpu.pc, NEXT_PC as u16);
-    }
+use super::*;
+const NEXT_PC: usize = START_PC + OPCODE_SIZE;
+const SKIPPED_PC: usize = START_PC + 2 * OPCODE_SIZE;
+fn init_cpu() -> Cpu {
+    let mut cpu = Cpu::new();
+    cpu.v = [0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7];
+    cpu
+}
+#[test]
+fn test_initial_state() {
+    let cpu = Cpu::new();
+    assert_eq!(cpu.pc, 0x200);
+    assert_eq!(cpu.sp, 0);
+    assert_eq!(cpu.stack, [0; 16]);
+    // First char in font: 0
+    assert_eq!(cpu.ram[0..5], [0xF0, 0x90, 0x90, 0x90, 0xF0]);
+    // Last char in font: F
+    assert_eq!(
+        cpu.ram[FONTSET.len() - 5..FONTSET.len()],
+        [0xF0, 0x80, 0xF0, 0x80, 0x80]
+    );
+}
+#[test]
+fn test_opcode_annn() {
+    let mut cpu = Cpu::new();
+    // Add opcode 0xA2F0 to ram
+    cpu.ram[0x200] = 0xA2;
+    cpu.ram[0x201] = 0xF0;
+    let initial_pc = cpu.pc;
+    // Check if the operation was successful
+    assert!(cpu.emulate_cycle().is_ok());
+    // Check if the 'I' register has a correct value
+    assert_eq!(cpu.i, 0x02F0);
+    // Check if the program counter was properly incremented
+    assert_eq!(cpu.pc, initial_pc + 2);
+}
+#[test]
+fn test_opcode_annn1() {
+    let mut cpu = init_cpu();
+    cpu.run_opcode(0xA2F0).unwrap();
+    assert_eq!(cpu.i, 0x02F0);
+    assert_eq!(cpu.pc, NEXT_PC as u16);
+}
 
-    // CLS
-    #[test]
-    fn test_op_00e0() {
-        let mut cpu = init_cpu();
-        cpu.run_opcode(0x00e0).unwrap();
+// CLS
+#[test]
+fn test_op_00e0() {
+    let mut cpu = init_cpu();
+    cpu.run_opcode(0x00e0).unwrap();
 
-        for x in 0..CHIP_8_WIDTH {
-            for y in 0..CHIP_8_HEIGHT {
-                assert_eq!(cpu.display[(y, x)], false);
-            }
+    for x in 0..CHIP_8_WIDTH {
+        for y in 0..CHIP_8_HEIGHT {
+            assert!(!cpu.display.get(x, y));
         }
-        assert_eq!(cpu.pc, NEXT_PC as u16);
     }
-    // RET
-    #[test]
-    fn test_op_00ee() {
-        let mut cpu = init_cpu();
-        cpu.sp = 6;
-        cpu.stack[5] = 0xaaaa;
-        cpu.run_opcode(0x00ee).unwrap();
-        assert_eq!(cpu.sp, 5);
-        assert_eq!(cpu.pc, 0xaaaa);
-    }
-    // JP addr
-    #[test]
-    fn test_op_1nnn() {
-        let mut cpu = init_cpu();
-        cpu.run_opcode(0x1abc).unwrap();
-        assert_eq!(cpu.pc, 0x0abc);
-    }
-    // CALL addr
-    #[test]
-    fn test_op_2nnn() {
-        let mut cpu = init_cpu();
-        cpu.run_opcode(0x2abc).unwrap();
-        assert_eq!(cpu.sp, 1);
-        assert_eq!(cpu.stack[0], START_PC as u16);
-        assert_eq!(cpu.pc, 0x0abc);
-    }
-    // SE Vx, byte
-    #[test]
-    fn test_op_3xkk_with_skip() {
-        let mut cpu = init_cpu();
-        cpu.v[5] = 0x00ab;
-        cpu.run_opcode(0x35ab).unwrap();
-        assert_eq!(cpu.pc, SKIPPED_PC as u16);
-    }
-    #[test]
-    fn test_op_3xkk_without_skip() {
-        let mut cpu = init_cpu();
-        cpu.v[5] = 0x00ab;
-        cpu.run_opcode(0x35ac).unwrap();
-        assert_eq!(cpu.pc, START_PC as u16);
-    }
-    // 4xkk - SNE Vx, byte
-    #[test]
-    fn test_op_4xkk_without_skip() {
-        let mut cpu = init_cpu();
-        cpu.v[10] = 0x00cd;
-        cpu.run_opcode(0x4acd).unwrap();
-        assert_eq!(cpu.pc, START_PC as u16);
-    }
-    #[test]
-    fn test_op_4xkk_with_skip() {
-        let mut cpu = init_cpu();
-        cpu.v[10] = 0x00cd;
-        cpu.run_opcode(0x4aca).unwrap();
-        assert_eq!(cpu.pc, SKIPPED_PC as u16);
-    }
-    // SE Vx, Vy
-    #[test]
-    fn test_op_5xy0_without_skip() {
-        let mut cpu = init_cpu();
-        cpu.v[5] = 0x10;
-        cpu.v[6] = 0x11;
-        cpu.run_opcode(0x5560).unwrap();
-        assert_eq!(cpu.pc, START_PC as u16);
-    }
-    #[test]
-    fn test_op_5xy0_with_skip() {
-        let mut cpu = init_cpu();
-        cpu.v[5] = 0x10;
-        cpu.v[6] = 0x10;
-        cpu.run_opcode(0x5560).unwrap();
-        assert_eq!(cpu.pc, SKIPPED_PC as u16);
-    }
-    // 6xkk - LD Vx, byte
-    #[test]
-    fn test_op_6xkk() {
-        let mut cpu = init_cpu();
-        cpu.run_opcode(0x6312).unwrap();
-        assert_eq!(cpu.v[3], 0x12);
-    }
-    // 7xkk - ADD Vx, byte
-    #[test]
-    fn test_op_7xkk_without_overflow() {
-        let mut cpu = init_cpu();
-        cpu.v[1] = 0x11;
-        cpu.run_opcode(0x7101).unwrap();
-        assert_eq!(cpu.v[1], 0x12);
-    }
-    // 8xy0 - LD Vx, Vy
-    #[test]
-    fn test_op_8xy0() {
-        let mut cpu = init_cpu();
-        cpu.v[1] = 0xAA;
-        cpu.run_opcode(0x8210).unwrap();
-        assert_eq!(cpu.v[2], 0xAA);
-    }
-    //8xy1 - OR Vx, Vy
-    #[test]
-    fn test_op_8xy1() {
-        let mut cpu = init_cpu();
-        cpu.v[1] = 0xA1;
-        cpu.v[2] = 0x1A;
-        cpu.run_opcode(0x8211).unwrap();
-        assert_eq!(cpu.v[2], 0xBB);
-    }
-    // 8xy2 - AND Vx, Vy
-    #[test]
-    fn test_op_8xy2() {
-        let mut cpu = init_cpu();
-        cpu.v[1] = 0x0B;
-        cpu.v[2] = 0xAF;
-        cpu.run_opcode(0x8212).unwrap();
-        assert_eq!(cpu.v[2], 0x0B);
-    }
-    // 8xy3 - XOR Vx, Vy
-    #[test]
-    fn test_op_8xy3() {
-        let mut cpu = init_cpu();
-        cpu.v[1] = 0x0B;
-        cpu.v[2] = 0xAF;
-        cpu.run_opcode(0x8213).unwrap();
-        assert_eq!(cpu.v[2], 0xA4);
-    }
-    // 8xy4 - ADD Vx, Vy
-    #[test]
-    fn test_op_8xy4_without_carry() {
+    assert_eq!(cpu.pc, NEXT_PC as u16);
+}
+// RET
+#[test]
+fn test_op_00ee() {
+    let mut cpu = init_cpu();
+    cpu.sp = 6;
+    cpu.stack[5] = 0xaaaa;
+    cpu.run_opcode(0x00ee).unwrap();
+    assert_eq!(cpu.sp, 5);
+    assert_eq!(cpu.pc, 0xaaaa);
+}
+#[test]
+fn test_op_00ee_stack_underflow() {
+    let mut cpu = init_cpu();
+    assert!(cpu.run_opcode(0x00ee).is_err());
+}
+#[test]
+fn test_op_2nnn_stack_overflow() {
+    let mut cpu = init_cpu();
+    cpu.sp = STACK_SIZE;
+    assert!(cpu.run_opcode(0x2abc).is_err());
+}
+#[test]
+fn test_op_fx55_out_of_bounds() {
+    let mut cpu = init_cpu();
+    cpu.i = RAM_SIZE - 2;
+    assert!(cpu.run_opcode(0xF355).is_err());
+}
+// JP addr
+#[test]
+fn test_op_1nnn() {
+    let mut cpu = init_cpu();
+    cpu.run_opcode(0x1abc).unwrap();
+    assert_eq!(cpu.pc, 0x0abc);
+}
+// CALL addr
+#[test]
+fn test_op_2nnn() {
+    let mut cpu = init_cpu();
+    cpu.run_opcode(0x2abc).unwrap();
+    assert_eq!(cpu.sp, 1);
+    assert_eq!(cpu.stack[0], NEXT_PC as u16);
+    assert_eq!(cpu.pc, 0x0abc);
+}
+// SE Vx, byte
+#[test]
+fn test_op_3xkk_with_skip() {
+    let mut cpu = init_cpu();
+    cpu.v[5] = 0x00ab;
+    cpu.run_opcode(0x35ab).unwrap();
+    assert_eq!(cpu.pc, SKIPPED_PC as u16);
+}
+#[test]
+fn test_op_3xkk_without_skip() {
+    let mut cpu = init_cpu();
+    cpu.v[5] = 0x00ab;
+    cpu.run_opcode(0x35ac).unwrap();
+    assert_eq!(cpu.pc, NEXT_PC as u16);
+}
+// 4xkk - SNE Vx, byte
+#[test]
+fn test_op_4xkk_without_skip() {
+    let mut cpu = init_cpu();
+    cpu.v[10] = 0x00cd;
+    cpu.run_opcode(0x4acd).unwrap();
+    assert_eq!(cpu.pc, NEXT_PC as u16);
+}
+#[test]
+fn test_op_4xkk_with_skip() {
+    let mut cpu = init_cpu();
+    cpu.v[10] = 0x00cd;
+    cpu.run_opcode(0x4aca).unwrap();
+    assert_eq!(cpu.pc, SKIPPED_PC as u16);
+}
+// SE Vx, Vy
+#[test]
+fn test_op_5xy0_without_skip() {
+    let mut cpu = init_cpu();
+    cpu.v[5] = 0x10;
+    cpu.v[6] = 0x11;
+    cpu.run_opcode(0x5560).unwrap();
+    assert_eq!(cpu.pc, NEXT_PC as u16);
+}
+#[test]
+fn test_op_5xy0_with_skip() {
+    let mut cpu = init_cpu();
+    cpu.v[5] = 0x10;
+    cpu.v[6] = 0x10;
+    cpu.run_opcode(0x5560).unwrap();
+    assert_eq!(cpu.pc, SKIPPED_PC as u16);
+}
+// 6xkk - LD Vx, byte
+#[test]
+fn test_op_6xkk() {
+    let mut cpu = init_cpu();
+    cpu.run_opcode(0x6312).unwrap();
+    assert_eq!(cpu.v[3], 0x12);
+}
+// 7xkk - ADD Vx, byte
+#[test]
+fn test_op_7xkk_without_overflow() {
+    let mut cpu = init_cpu();
+    cpu.v[1] = 0x11;
+    cpu.run_opcode(0x7101).unwrap();
+    assert_eq!(cpu.v[1], 0x12);
+}
+// 8xy0 - LD Vx, Vy
+#[test]
+fn test_op_8xy0() {
+    let mut cpu = init_cpu();
+    cpu.v[1] = 0xAA;
+    cpu.run_opcode(0x8210).unwrap();
+    assert_eq!(cpu.v[2], 0xAA);
+}
+//8xy1 - OR Vx, Vy
+#[test]
+fn test_op_8xy1() {
+    let mut cpu = init_cpu();
+    cpu.v[1] = 0xA1;
+    cpu.v[2] = 0x1A;
+    cpu.run_opcode(0x8211).unwrap();
+    assert_eq!(cpu.v[2], 0xBB);
+}
+// 8xy2 - AND Vx, Vy
+#[test]
+fn test_op_8xy2() {
+    let mut cpu = init_cpu();
+    cpu.v[1] = 0x0B;
+    cpu.v[2] = 0xAF;
+    cpu.run_opcode(0x8212).unwrap();
+    assert_eq!(cpu.v[2], 0x0B);
+}
+// 8xy3 - XOR Vx, Vy
+#[test]
+fn test_op_8xy3() {
+    let mut cpu = init_cpu();
+    cpu.v[1] = 0x0B;
+    cpu.v[2] = 0xAF;
+    cpu.run_opcode(0x8213).unwrap();
+    assert_eq!(cpu.v[2], 0xA4);
+}
+// 8xy4 - ADD Vx, Vy
+#[test]
+fn test_op_8xy4_without_carry() {
+    let mut cpu = init_cpu();
+    cpu.v[1] = 0x0B;
+    cpu.v[2] = 0xAF;
+    cpu.run_opcode(0x8214).unwrap();
+    assert_eq!(cpu.v[2], 0xBA);
+    assert_eq!(cpu.v[0xF], 0);
+}
+#[test]
+fn test_op_8xy4_with_carry() {
+    let mut cpu = init_cpu();
+    cpu.v[1] = 0xFF;
+    cpu.v[2] = 0xAF;
+    cpu.run_opcode(0x8214).unwrap();
+    assert_eq!(cpu.v[2], 0xAE);
+    assert_eq!(cpu.v[0xF], 1);
+}
+// 8xy5 - SUB Vx, Vy
+#[test]
+fn test_op_8xy5_without_underflow() {
+    let mut cpu = init_cpu();
+    cpu.v[1] = 0xFF;
+    cpu.v[2] = 0xAF;
+    cpu.run_opcode(0x8125).unwrap();
+    assert_eq!(cpu.v[1], 0x50);
+    assert_eq!(cpu.v[0xF], 1);
+}
+#[test]
+fn test_op_8xy5_with_underflow() {
+    let mut cpu = init_cpu();
+    cpu.v[1] = 0x00;
+    cpu.v[2] = 0x01;
+    cpu.run_opcode(0x8125).unwrap();
+    assert_eq!(cpu.v[1], 0xFF);
+    assert_eq!(cpu.v[0xF], 0);
+}
+// 8xy6 - SHR Vx {, Vy}
+#[test]
+fn test_op_8xy6_with_lsb_one() {
+    let mut cpu = init_cpu();
+    // The shift quirk copies Vy into Vx first
+    cpu.v[2] = 0xAF;
+    cpu.run_opcode(0x8126).unwrap();
+    assert_eq!(cpu.v[1], 0x57);
+    assert_eq!(cpu.v[0xF], 1);
+}
+#[test]
+fn test_op_8xy6_with_lsb_zero() {
+    let mut cpu = init_cpu();
+    cpu.v[2] = 0xAE;
+    cpu.run_opcode(0x8126).unwrap();
+    assert_eq!(cpu.v[1], 0x57);
+    assert_eq!(cpu.v[0xF], 0);
+}
+#[test]
+fn test_reset() {
+    let mut cpu = init_cpu();
+    cpu.load_rom(vec![0x60, 0x05, 0xF0, 0x18, 0x00, 0xE0]).unwrap();
+    cpu.v[3] = 7;
+    cpu.i = 0x300;
+    cpu.sp = 2;
+    cpu.pc = 0x400;
+    cpu.st = 10;
+    cpu.beep = true;
+    cpu.display.set(5, 5, true);
+    cpu.ram[0] = 0xFF; // clobber the fonts
+    cpu.press_key(4);
+    cpu.reset();
+    let fresh = Cpu::new();
+    assert_eq!(cpu.ram, fresh.ram);
+    assert_eq!((cpu.v, cpu.i, cpu.sp, cpu.pc), (fresh.v, fresh.i, fresh.sp, fresh.pc));
+    assert_eq!((cpu.dt, cpu.st, cpu.beep, cpu.frames), (0, 0, false, 0));
+    assert!(!cpu.display.get(5, 5));
+    assert!(!cpu.key_down(4));
+}
+#[test]
+fn test_random_seed() {
+    // Cxkk masks a random byte, the same ones again for the same seed
+    let randoms = |seed| {
         let mut cpu = init_cpu();
-        cpu.v[1] = 0x0B;
-        cpu.v[2] = 0xAF;
-        cpu.run_opcode(0x8214).unwrap();
-        assert_eq!(cpu.v[2], 0xBA);
-        assert_eq!(cpu.v[0xF], 0);
+        cpu.seed(seed);
+        (0..16).map(|_| { cpu.run_opcode(0xC0FF).unwrap(); cpu.v[0] }).collect::<Vec<u8>>()
+    };
+    assert_eq!(randoms(1), randoms(1));
+    assert_ne!(randoms(1), randoms(2));
+    assert!(randoms(0).iter().any(|&r| r != 0));
+    let mut cpu = init_cpu();
+    cpu.seed(7);
+    cpu.run_opcode(0xC10F).unwrap();
+    assert!(cpu.v[1] <= 0x0F);
+    let first = cpu.v[1];
+    cpu.reset();
+    cpu.run_opcode(0xC10F).unwrap();
+    assert_eq!(cpu.v[1], first);
+}
+#[test]
+fn test_engines_see_self_modifying_code() {
+    // Calls LD VB, 0x01; ADD VB, 0x01 at 0x20E, overwrites the add with ADD VB, 0x05 through Fx55
+    // and calls it again
+    let rom = vec![
+        0x22, 0x0E, 0xA2, 0x10, 0x60, 0x7B, 0x61, 0x05, 0xF1, 0x55, 0x22, 0x0E, 0x12, 0x0C, 0x6B, 0x01, 0x7B, 0x01,
+        0x00, 0xEE,
+    ];
+    for engine in [Engine::Interpreter, Engine::Cached, Engine::Recompiler] {
+        let mut cpu = Cpu::new();
+        cpu.engine = engine;
+        cpu.speed = 1000;
+        cpu.load_rom(rom.clone()).unwrap();
+        let mut cycles = 0;
+        while cycles < 13 {
+            cycles += cpu.emulate_cycles().unwrap();
+        }
+        assert_eq!(cycles, 13);
+        assert_eq!(cpu.v[0xB], 6, "{}", engine.name());
+        // The interpreter has no use for the cache, and doesn't allocate it
+        assert_eq!(cpu.cache.is_some(), engine != Engine::Interpreter);
+        assert_eq!(cpu.pc, 0x20C);
+        // A new rom replaces what was cached or compiled
+        cpu.reset();
+        cpu.load_rom(vec![0x6B, 0x2A, 0x7B, 0x01]).unwrap();
+        let ran = cpu.emulate_cycles().unwrap();
+        assert_eq!(ran, if engine == Engine::Recompiler { 2 } else { 1 });
+        assert_eq!(cpu.v[0xB], 0x2A + ran as u8 - 1);
     }
-    #[test]
-    fn test_op_8xy4_with_carry() {
-        let mut cpu = init_cpu();
-        cpu.v[1] = 0xFF;
-        cpu.v[2] = 0xAF;
-        cpu.run_opcode(0x8214).unwrap();
-        assert_eq!(cpu.v[2], 0xAE);
-        assert_eq!(cpu.v[0xF], 1);
+    assert_eq!("recompiler".parse(), Ok(Engine::Recompiler));
+    assert!("jit".parse::<Engine>().is_err());
+}
+#[test]
+fn test_quirk_profiles() {
+    assert_eq!(Quirks::default().name(), Some("chip-8"));
+    assert_eq!(Platform::Schip.quirks().name(), Some("schip"));
+    assert!(Quirks::profile("cosmac").is_err());
+    let quirks = Quirks {
+        wrap: true,
+        ..Default::default()
+    };
+    assert_eq!(quirks.name(), None);
+    assert_eq!("xo-chip".parse(), Ok(Platform::XoChip));
+}
+#[test]
+fn test_shift_quirk() {
+    let mut cpu = init_cpu();
+    cpu.quirks.shift = true;
+    cpu.v[1] = 0x03;
+    cpu.run_opcode(0x8126).unwrap();
+    assert_eq!(cpu.v[1], 0x01);
+    assert_eq!(cpu.v[0xF], 1);
+}
+#[test]
+fn test_logic_quirk() {
+    let mut cpu = init_cpu();
+    cpu.quirks.logic = false;
+    cpu.v[0xF] = 5;
+    cpu.run_opcode(0x8121).unwrap();
+    assert_eq!(cpu.v[0xF], 5);
+}
+#[test]
+fn test_memory_quirks() {
+    let mut cpu = init_cpu();
+    cpu.i = 0x300;
+    cpu.run_opcode(0xF255).unwrap();
+    assert_eq!(cpu.i, 0x300);
+    cpu.quirks.memory_leave_i_unchanged = false;
+    cpu.run_opcode(0xF255).unwrap();
+    assert_eq!(cpu.i, 0x303);
+    cpu.quirks.memory_increment_by_x = true;
+    cpu.run_opcode(0xF265).unwrap();
+    assert_eq!(cpu.i, 0x305);
+}
+#[test]
+fn test_jump_quirk() {
+    let mut cpu = init_cpu();
+    cpu.quirks.jump = true;
+    cpu.v[3] = 0x10;
+    cpu.run_opcode(0xB300).unwrap();
+    assert_eq!(cpu.pc, 0x310);
+}
+#[test]
+fn test_wrap_quirk() {
+    let mut cpu = init_cpu();
+    cpu.quirks.wrap = true;
+    cpu.i = 0x300;
+    cpu.ram[0x300] = 0xFF;
+    cpu.v[0] = 60;
+    cpu.run_opcode(0xD011).unwrap();
+    assert!(cpu.display.get(63, 0));
+    assert!(cpu.display.get(0, 0) && cpu.display.get(3, 0));
+}
+#[test]
+fn test_vblank_quirk() {
+    let mut cpu = Cpu::new();
+    cpu.quirks.vblank = true;
+    // Draw, then set V1 once the next frame starts
+    cpu.load_rom(vec![0xD0, 0x01, 0x61, 0x01, 0x12, 0x04]).unwrap();
+    cpu.emulate_cycle().unwrap();
+    cpu.emulate_cycle().unwrap();
+    assert_eq!(cpu.v[1], 0);
+    while cpu.frames() == 0 {
+        cpu.emulate_cycle().unwrap();
     }
-    // 8xy5 - SUB Vx, Vy
-    #[test]
-    fn test_op_8xy5_without_underflow() {
-        let mut cpu = init_cpu();
-        cpu.v[1] = 0xFF;
-        cpu.v[2] = 0xAF;
-        cpu.run_opcode(0x8125).unwrap();
-        assert_eq!(cpu.v[1], 0x50);
-        assert_eq!(cpu.v[0xF], 1);
+    assert_eq!(cpu.v[1], 1);
+}
+
+// Property tests: whatever the ROM and machine state, the interpreter
+// must report bad programs through CpuError instead of panicking
+mod properties {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn check_invariants(cpu: &Cpu) {
+        assert!(cpu.sp <= STACK_SIZE);
+        assert!((cpu.pc as usize) < RAM_SIZE);
+        assert_eq!(cpu.display.width(), CHIP_8_WIDTH);
+        assert_eq!(cpu.display.height(), CHIP_8_HEIGHT);
     }
-    #[test]
-    fn test_op_8xy5_with_underflow() {
-        let mut cpu = init_cpu();
-        cpu.v[1] = 0x00;
-        cpu.v[2] = 0x01;
-        cpu.run_opcode(0x8125).unwrap();
-        assert_eq!(cpu.v[1], 0xFF);
-        assert_eq!(cpu.v[0xF], 0);
+
+    fn run(cpu: &mut Cpu, cycles: usize) {
+        for _ in 0..cycles {
+            if cpu.emulate_cycle().is_err() {
+                break;
+            }
+            check_invariants(cpu);
+        }
     }
-    // 8xy6 - SHR Vx {, Vy}
-    #[test]
-    fn test_op_8xy6_with_lsb_one() {
-        let mut cpu = init_cpu();
-        cpu.v[1] = 0xAF;
-        cpu.run_opcode(0x8126).unwrap();
-        assert_eq!(cpu.v[1], 0x57);
-        assert_eq!(cpu.v[0xF], 1);
+
+    // Mostly instructions that go in blocks, with the odd branch, skip or write in between
+    fn straight_line_rom() -> impl Strategy<Value = Vec<u8>> {
+        let opcode = prop_oneof![
+            4 => (0_u16..=0xFFF).prop_map(|low| [0x6000, 0x7000, 0xA000, 0xC000][(low % 4) as usize] | low),
+            2 => (0_u16..=0xFF).prop_map(|xy| 0x8000 | xy << 4 | [0, 1, 2, 3, 4, 5, 6, 7, 0xE][(xy % 9) as usize]),
+            1 => (0_u16..=0xF).prop_map(|x| 0xF000 | x << 8 | [0x07, 0x15, 0x18, 0x1E, 0x29, 0x33, 0x55][x as usize % 7]),
+            1 => any::<u16>(),
+        ];
+        prop::collection::vec(opcode, 0..512).prop_map(|opcodes| opcodes.iter().flat_map(|o| o.to_be_bytes()).collect())
     }
-    #[test]
-    fn test_op_8xy6_with_lsb_zero() {
-        let mut cpu = init_cpu();
-        cpu.v[1] = 0xAE;
-        cpu.run_opcode(0x8126).unwrap();
-        assert_eq!(cpu.v[1], 0x57);
-        assert_eq!(cpu.v[0xF], 0);
+
+    // Run the recompiler a call to emulate_cycles at a time, and the interpreter for as many
+    // cycles, which has to leave both in the same state every time
+    fn check_recompiler(rom: Vec<u8>, speed: u32) -> Result<(), TestCaseError> {
+        let mut interpreter = Cpu::new();
+        let mut recompiler = Cpu::new();
+        recompiler.engine = Engine::Recompiler;
+        for cpu in [&mut interpreter, &mut recompiler] {
+            cpu.speed = speed;
+            cpu.load_rom(rom.clone()).unwrap();
+        }
+        let mut cycles = 0;
+        while cycles < 2000 {
+            let result = recompiler.emulate_cycles().map_err(|e| e.to_string());
+            let ran = *result.as_ref().unwrap_or(&1);
+            let mut expected = Ok(());
+            let mut instructions = vec![];
+            for _ in 0..ran {
+                expected = interpreter.emulate_cycle().map_err(|e| e.to_string());
+                instructions.extend(interpreter.last_instruction);
+            }
+            prop_assert_eq!(result.map(|_| ()), expected.clone());
+            prop_assert_eq!(recompiler.last_instructions(), &instructions[..]);
+            prop_assert_eq!(recompiler.last_instruction, interpreter.last_instruction);
+            prop_assert_eq!((recompiler.pc, recompiler.i, recompiler.sp, recompiler.v), (interpreter.pc, interpreter.i, interpreter.sp, interpreter.v));
+            prop_assert_eq!((recompiler.dt, recompiler.st, recompiler.timer_counter, recompiler.frames), (interpreter.dt, interpreter.st, interpreter.timer_counter, interpreter.frames));
+            prop_assert!(recompiler.ram == interpreter.ram && recompiler.display == interpreter.display);
+            if expected.is_err() {
+                break;
+            }
+            cycles += ran;
+        }
+        Ok(())
     }
-    
 
+    proptest! {
+        #[test]
+        fn recompiler_matches_interpreter(rom in straight_line_rom(), speed in prop_oneof![Just(DEFAULT_SPEED), 1_u32..2000]) {
+            check_recompiler(rom, speed)?;
+        }
+
+        #[test]
+        fn recompiler_matches_interpreter_on_any_rom(rom in prop::collection::vec(any::<u8>(), 0..RAM_SIZE - START_PC)) {
+            check_recompiler(rom, 1000)?;
+        }
+
+        #[test]
+        fn arbitrary_rom_never_panics(rom in prop::collection::vec(any::<u8>(), 0..RAM_SIZE - START_PC)) {
+            let mut cpu = Cpu::new();
+            cpu.load_rom(rom).unwrap();
+            run(&mut cpu, 2000);
+        }
+
+        #[test]
+        fn cached_engine_matches_interpreter(rom in prop::collection::vec(any::<u8>(), 0..RAM_SIZE - START_PC)) {
+            let mut interpreter = Cpu::new();
+            let mut cached = Cpu::new();
+            cached.engine = Engine::Cached;
+            interpreter.load_rom(rom.clone()).unwrap();
+            cached.load_rom(rom).unwrap();
+            for _ in 0..2000 {
+                let expected = interpreter.emulate_cycle().map_err(|e| e.to_string());
+                prop_assert_eq!(cached.emulate_cycle().map_err(|e| e.to_string()), expected.clone());
+                prop_assert_eq!(cached.last_instruction, interpreter.last_instruction);
+                prop_assert_eq!((cached.pc, cached.i, cached.sp, cached.v), (interpreter.pc, interpreter.i, interpreter.sp, interpreter.v));
+                prop_assert!(cached.ram == interpreter.ram && cached.display == interpreter.display);
+                if expected.is_err() {
+                    break;
+                }
+            }
+        }
+
+        #[test]
+        fn oversized_rom_is_rejected(extra in 1_usize..64) {
+            let mut cpu = Cpu::new();
+            assert!(cpu.load_rom(vec![0; RAM_SIZE - START_PC + extra]).is_err());
+        }
+
+        #[test]
+        fn arbitrary_state_never_panics(
+            opcodes in prop::collection::vec(any::<u16>(), 1..64),
+            v in any::<[u8; 16]>(),
+            i in 0_usize..=0xFFFF,
+            sp in 0_usize..=STACK_SIZE,
+            stack in any::<[u16; STACK_SIZE]>(),
+            pc in 0_u16..=ADDRESS_MASK,
+            keys in any::<u16>(),
+        ) {
+            let mut cpu = Cpu::new();
+            cpu.v = v;
+            cpu.i = i;
+            cpu.sp = sp;
+            // The stack only ever holds return addresses
+            cpu.stack = stack.map(|a| a & ADDRESS_MASK);
+            cpu.pc = pc;
+            for k in 0..16 {
+                if keys >> k & 1 == 1 {
+                    cpu.press_key(k);
+                }
+            }
+            // Run the opcodes both directly and from RAM
+            for &opcode in &opcodes {
+                if cpu.run_opcode(opcode).is_err() {
+                    break;
+                }
+                check_invariants(&cpu);
+            }
+            for (idx, &opcode) in opcodes.iter().enumerate() {
+                let addr = (pc as usize + idx * OPCODE_SIZE) % (RAM_SIZE - 1);
+                cpu.ram[addr] = (opcode >> 8) as u8;
+                cpu.ram[addr + 1] = opcode as u8;
+            }
+            run(&mut cpu, opcodes.len());
+        }
+    }
 }
@@ -0,0 +1,38 @@
+// A log of every instruction run, for finding out where a rom goes wrong
+//
+//     frame    address  opcode  instruction
+//     12       0x22A    D015    DRW V0, V1, 5
+use crate::disasm::disassemble;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+pub struct Trace {
+    out: Box<dyn Write>,
+    error: Option<io::Error>, // the first write that failed, tracing stops there
+}
+
+impl Trace {
+    pub fn new(out: Box<dyn Write>) -> Self {
+        Trace { out, error: None }
+    }
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self::new(Box::new(BufWriter::new(File::create(path)?))))
+    }
+    // Called by the driver for every instruction
+    pub fn instruction(&mut self, frame: u64, address: u16, opcode: u16) {
+        if self.error.is_some() {
+            return;
+        }
+        let line = format!("{:<8} {:#05X}    {:04X}    {}", frame, address, opcode, disassemble(opcode));
+        if let Err(e) = writeln!(self.out, "{}", line) {
+            self.error = Some(e);
+        }
+    }
+    pub fn finish(mut self) -> io::Result<()> {
+        match self.error {
+            Some(e) => Err(e),
+            None => self.out.flush(),
+        }
+    }
+}
@@ -0,0 +1,344 @@
+// Windowed frontend, drawn with piston and OpenGL
+use chip8::cpu::{CHIP_8_HEIGHT, CHIP_8_WIDTH};
+use chip8::capture::{self, ScreenshotSettings};
+use chip8::crash::CRASH_KEYS;
+use chip8::driver::{Driver, FAST_FORWARD_SPEED};
+use chip8::keymap::{char_name, Keymap};
+use chip8::osd::{self, Osd, GLYPH_HEIGHT};
+use chip8::palette::{Colour, PaletteCycle};
+use chip8::record::RecordSettings;
+use chip8::scheduler::Scheduler;
+use glutin_window::GlutinWindow;
+use opengl_graphics::{CreateTexture, Filter, Format, GlGraphics, OpenGL, Texture, TextureSettings, UpdateTexture};
+use piston::event_loop::{EventSettings, Events};
+use piston::{Button, ButtonEvent, ButtonState, Key, RenderEvent, UpdateEvent, Window, WindowSettings};
+use std::panic::{self, AssertUnwindSafe};
+use std::str::FromStr;
+use std::time::Instant;
+
+const FPS: u64 = 60;
+const LETTERBOX: Colour = [0.0, 0.0, 0.0, 1.0];
+// The COSMAC VIP showed its 64x32 pixels on a 4:3 TV, so each pixel was 1.5 times taller than wide
+const VIP_PIXEL_ASPECT: f64 = 1.5;
+// The OSD text is drawn with each font pixel this many window pixels wide
+const OSD_SCALE: f64 = 2.0;
+const OSD_MARGIN: f64 = 4.0;
+const OSD_TEXT: Colour = [1.0, 1.0, 1.0, 1.0];
+const OSD_BACKGROUND: Colour = [0.0, 0.0, 0.0, 0.6];
+// Laid over the frozen game behind a crash report
+const CRASH_DIM: Colour = [0.3, 0.0, 0.0, 0.6];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scaling {
+    Integer, // whole multiples of the pixel size only, so every pixel is the same size
+    Fit,     // as large as fits in the window
+}
+
+impl FromStr for Scaling {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "integer" => Ok(Scaling::Integer),
+            "fit" => Ok(Scaling::Fit),
+            _ => Err(format!("Unknown scaling: {}", s)),
+        }
+    }
+}
+
+pub struct WindowOptions {
+    pub scale: f64, // initial size of a pixel in the window
+    pub scaling: Scaling,
+    pub vip_aspect: bool,
+    pub fullscreen: bool,
+}
+
+// Where the screen goes in the window, the rest is letterboxed
+#[derive(Debug, PartialEq)]
+struct Layout {
+    x: f64,
+    y: f64,
+    pixel_width: f64,
+    pixel_height: f64,
+}
+
+fn layout(window_size: [f64; 2], scaling: Scaling, pixel_aspect: f64) -> Layout {
+    let [w, h] = window_size;
+    let fit = (w / CHIP_8_WIDTH as f64).min(h / (CHIP_8_HEIGHT as f64 * pixel_aspect));
+    let scale = match scaling {
+        // Fall back to fitting when the window is smaller than one pixel per pixel
+        Scaling::Integer if fit >= 1.0 => fit.floor(),
+        _ => fit,
+    };
+    let pixel_width = scale;
+    let pixel_height = scale * pixel_aspect;
+    Layout {
+        x: ((w - pixel_width * CHIP_8_WIDTH as f64) / 2.0).floor(),
+        y: ((h - pixel_height * CHIP_8_HEIGHT as f64) / 2.0).floor(),
+        pixel_width,
+        pixel_height,
+    }
+}
+
+// The name a key is bound by in the keymap
+fn key_name(key: Key) -> Option<String> {
+    let name = match key {
+        Key::Up => "up",
+        Key::Down => "down",
+        Key::Left => "left",
+        Key::Right => "right",
+        Key::Return => "enter",
+        Key::Tab => "tab",
+        Key::Backspace => "backspace",
+        Key::NumPad0 => "kp0",
+        Key::NumPad1 => "kp1",
+        Key::NumPad2 => "kp2",
+        Key::NumPad3 => "kp3",
+        Key::NumPad4 => "kp4",
+        Key::NumPad5 => "kp5",
+        Key::NumPad6 => "kp6",
+        Key::NumPad7 => "kp7",
+        Key::NumPad8 => "kp8",
+        Key::NumPad9 => "kp9",
+        // Printable keys have their ASCII code
+        _ => {
+            return u8::try_from(key.code())
+                .ok()
+                .map(char::from)
+                .filter(|c| c.is_ascii_graphic() || *c == ' ')
+                .map(char_name)
+        }
+    };
+    Some(name.to_string())
+}
+
+// winit panics instead of failing when there's no display at all, which is as much an error
+fn build_window(settings: &WindowSettings) -> Result<GlutinWindow, String> {
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let result = panic::catch_unwind(AssertUnwindSafe(|| settings.build::<GlutinWindow>()));
+    panic::set_hook(hook);
+    match result {
+        Ok(window) => window.map_err(|e| e.to_string()),
+        Err(payload) => Err(payload
+            .downcast_ref::<String>()
+            .cloned()
+            .or_else(|| payload.downcast_ref::<&str>().map(|s| s.to_string()))
+            .unwrap_or_else(|| "no display".to_string())),
+    }
+}
+
+pub fn start_window(
+    driver: &mut Driver,
+    palettes: &mut PaletteCycle,
+    osd: &mut Osd,
+    keymap: &Keymap,
+    screenshots: &ScreenshotSettings,
+    recordings: &RecordSettings,
+    options: &WindowOptions,
+) -> Result<(), String> {
+    let pixel_aspect = if options.vip_aspect { VIP_PIXEL_ASPECT } else { 1.0 };
+    // Initialize settings
+    let w_width: f64 = CHIP_8_WIDTH as f64 * options.scale;
+    let w_height: f64 = CHIP_8_HEIGHT as f64 * options.scale * pixel_aspect;
+    let settings = WindowSettings::new("Chip 8 Emulator", (w_width, w_height))
+        .exit_on_esc(true)
+        .fullscreen(options.fullscreen);
+    // Create window
+    let mut window = build_window(&settings).map_err(|e| format!("Could not create a window: {}", e))?;
+    let mut fullscreen = options.fullscreen;
+    // The size pixels were last drawn at, for screenshots
+    let mut drawn = (options.scale as usize, (options.scale * pixel_aspect) as usize);
+    // Create the event
+    let mut event_settings = EventSettings::new();
+    // Updates only wake the loop up, the scheduler decides how many frames to run. Twice a frame,
+    // so no frame runs more than half a frame late
+    event_settings.ups = FPS * 2;
+    event_settings.max_fps = FPS; // 60
+    let mut events = Events::new(event_settings);
+    // Initialize OpenGL
+    let opengl = OpenGL::V3_2;
+    let mut gl = GlGraphics::new(opengl);
+    // The screen is one texture with a texel per pixel, blown up without smoothing when it's drawn.
+    // It is only uploaded again when the picture changes
+    let texture_settings = TextureSettings::new().filter(Filter::Nearest).convert_gamma(true);
+    let mut texture: Option<Texture> = None;
+    let mut uploaded: Vec<u8> = vec![];
+    let mut scheduler = Scheduler::new(Instant::now());
+    while let Some(e) = events.next(&mut window) {
+        if e.update_args().is_some() {
+            // Time keeps passing while paused, it just isn't run
+            let due = scheduler.frames_due(Instant::now());
+            if !driver.paused {
+                let frames = due * if driver.fast_forward { FAST_FORWARD_SPEED } else { 1 };
+                for _ in 0..frames {
+                    // A failed instruction pauses the driver, which then shows the crash
+                    if driver.run_frame().is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+        // Capture a keypress and send it to the CPU
+        if let Some(b) = e.button_args() {
+            // After a crash the keys are for what to do about it, releases still reach the CPU
+            if driver.crash.is_some() && b.state == ButtonState::Press {
+                match b.button {
+                    Button::Keyboard(Key::R) => driver.reset(),
+                    Button::Keyboard(Key::C) => driver.resume(),
+                    Button::Keyboard(Key::Q) => window.set_should_close(true),
+                    _ => {}
+                }
+                continue;
+            }
+            if b.state == ButtonState::Press {
+                match b.button {
+                    Button::Keyboard(Key::M) => driver.sink.set_muted(!driver.sink.is_muted()),
+                    Button::Keyboard(Key::F1) => driver.set_paused(!driver.paused),
+                    // Errors show up as the crash
+                    Button::Keyboard(Key::F2) if driver.paused => driver.run_frame().unwrap_or(()),
+                    Button::Keyboard(Key::F3) if driver.paused => driver.step().unwrap_or(()),
+                    Button::Keyboard(Key::F4) => {
+                        driver.reset();
+                        osd.message("Reset", Instant::now());
+                    }
+                    Button::Keyboard(Key::F5) => {
+                        let message = match driver.reload() {
+                            Ok(()) => "Reloaded".to_string(),
+                            Err(e) => e,
+                        };
+                        osd.message(message, Instant::now());
+                    }
+                    Button::Keyboard(Key::F6) => driver.fast_forward = !driver.fast_forward,
+                    Button::Keyboard(Key::F7) => {
+                        let palette = palettes.advance();
+                        if let Some(recorder) = &mut driver.recorder {
+                            recorder.set_palette(palette);
+                        }
+                        osd.message(format!("Palette: {}", palette.name), Instant::now());
+                    }
+                    Button::Keyboard(Key::F8) => {
+                        let message = recordings.toggle(driver, palettes.current());
+                        osd.message(message, Instant::now());
+                    }
+                    Button::Keyboard(Key::F9) => osd.visible = !osd.visible,
+                    Button::Keyboard(Key::F12) => {
+                        let message = match screenshots.save(driver, palettes.current(), drawn) {
+                            Ok(path) => format!("Saved {}", path.display()),
+                            Err(e) => format!("Screenshot failed: {}", e),
+                        };
+                        osd.message(message, Instant::now());
+                    }
+                    Button::Keyboard(Key::F11) => {
+                        fullscreen = !fullscreen;
+                        let mode = fullscreen.then_some(winit::window::Fullscreen::Borderless(None));
+                        window.window.set_fullscreen(mode);
+                    }
+                    _ => {}
+                }
+            }
+            let name = match b.button {
+                Button::Keyboard(key) => key_name(key),
+                _ => None,
+            };
+            if let Some(k) = keymap.lookup(name.as_deref(), b.scancode) {
+                match b.state {
+                    ButtonState::Press => driver.cpu.press_key(k),
+                    ButtonState::Release => driver.cpu.release_key(k),
+                }
+            }
+        }
+        if let Some(r) = e.render_args() {
+            driver.poll_inputs();
+            // Recomputed every frame, so resizing and fullscreen just work
+            let screen = layout(r.window_size, options.scaling, pixel_aspect);
+            drawn = (screen.pixel_width.round() as usize, screen.pixel_height.round() as usize);
+            let frame = capture::capture(driver, palettes.current(), 1, 1).to_rgba();
+            if texture.is_none() || frame != uploaded {
+                let size = [CHIP_8_WIDTH as u32, CHIP_8_HEIGHT as u32];
+                match &mut texture {
+                    Some(texture) => UpdateTexture::update(texture, &mut (), Format::Rgba8, &frame, [0, 0], size),
+                    None => Texture::create(&mut (), Format::Rgba8, &frame, size, &texture_settings)
+                        .map(|created| texture = Some(created)),
+                }
+                .map_err(|e| format!("Could not upload the screen: {}", e))?;
+                uploaded = frame;
+            }
+            osd.update(driver, Instant::now());
+            let lines = match &driver.crash {
+                Some(crash) => {
+                    let mut lines = crash.lines();
+                    lines.push(CRASH_KEYS.to_string());
+                    lines
+                }
+                None => osd.lines(driver),
+            };
+            gl.draw(r.viewport(), |c, g| {
+                graphics::clear(LETTERBOX, g);
+                // The whole screen in one quad
+                if let Some(texture) = &texture {
+                    let width = screen.pixel_width * CHIP_8_WIDTH as f64;
+                    let height = screen.pixel_height * CHIP_8_HEIGHT as f64;
+                    let rect = [screen.x, screen.y, width, height];
+                    graphics::Image::new().rect(rect).draw(texture, &c.draw_state, c.transform, g);
+                }
+                if driver.crash.is_some() {
+                    let [w, h] = r.window_size;
+                    graphics::Rectangle::new(CRASH_DIM).draw([0.0, 0.0, w, h], &c.draw_state, c.transform, g);
+                }
+                // The OSD goes on top, in the corner of the window rather than the screen
+                for (idx, line) in lines.iter().enumerate() {
+                    let x = OSD_MARGIN;
+                    let y = OSD_MARGIN + (idx * (GLYPH_HEIGHT + 2)) as f64 * OSD_SCALE;
+                    let width = (osd::text_width(line) + 2) as f64 * OSD_SCALE;
+                    let height = (GLYPH_HEIGHT + 2) as f64 * OSD_SCALE;
+                    let background = [x, y, width, height];
+                    graphics::Rectangle::new(OSD_BACKGROUND).draw(background, &c.draw_state, c.transform, g);
+                    for (px, py) in osd::text_pixels(line) {
+                        let pos = [
+                            x + (px + 1) as f64 * OSD_SCALE,
+                            y + (py + 1) as f64 * OSD_SCALE,
+                            OSD_SCALE,
+                            OSD_SCALE,
+                        ];
+                        graphics::Rectangle::new(OSD_TEXT).draw(pos, &c.draw_state, c.transform, g);
+                    }
+                }
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_integer_layout_is_centered() {
+        let screen = layout([1000.0, 600.0], Scaling::Integer, 1.0);
+        assert_eq!(screen.pixel_width, 15.0);
+        assert_eq!(screen.pixel_height, 15.0);
+        assert_eq!(screen.x, 20.0);
+        assert_eq!(screen.y, 60.0);
+    }
+    #[test]
+    fn test_fit_layout_fills_window() {
+        let screen = layout([1000.0, 600.0], Scaling::Fit, 1.0);
+        assert_eq!(screen.pixel_width, 15.625);
+        assert_eq!(screen.x, 0.0);
+    }
+    #[test]
+    fn test_key_names() {
+        assert_eq!(key_name(Key::Q).as_deref(), Some("q"));
+        assert_eq!(key_name(Key::D1).as_deref(), Some("1"));
+        assert_eq!(key_name(Key::Space).as_deref(), Some("space"));
+        assert_eq!(key_name(Key::Left).as_deref(), Some("left"));
+        assert_eq!(key_name(Key::F1), None);
+    }
+    #[test]
+    fn test_vip_aspect_layout() {
+        let screen = layout([640.0, 480.0], Scaling::Fit, VIP_PIXEL_ASPECT);
+        assert_eq!(screen.pixel_width, 10.0);
+        assert_eq!(screen.pixel_height, 15.0);
+        assert_eq!((screen.x, screen.y), (0.0, 0.0));
+    }
+}
@@ -0,0 +1,98 @@
+// Headless audio: the WAV file must line up with the emulated frames, and pauses and crashes must be quiet
+use chip8::audio::{AudioSink, ToneSettings, WavSink, SAMPLES_PER_FRAME};
+use chip8::cpu::Cpu;
+use chip8::driver::Driver;
+use std::cell::Cell;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+fn render_wav(name: &str, rom: Vec<u8>, frames: u64) -> Vec<i16> {
+    let path = env::temp_dir().join(format!("chip8-{}-{}.wav", name, std::process::id()));
+    let mut cpu = Cpu::new();
+    cpu.load_rom(rom).unwrap();
+    let sink = WavSink::create(&path, ToneSettings::default()).unwrap();
+    let mut driver = Driver::new(cpu, Box::new(sink));
+    for _ in 0..frames {
+        driver.run_frame().unwrap();
+    }
+    driver.sink.finish().unwrap();
+    let bytes = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(&bytes[0..4], b"RIFF");
+    assert_eq!(&bytes[8..12], b"WAVE");
+    let data_size = u32::from_le_bytes(bytes[40..44].try_into().unwrap()) as usize;
+    assert_eq!(data_size, bytes.len() - 44);
+    bytes[44..].chunks(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect()
+}
+
+#[test]
+fn test_wav_is_sample_accurate() {
+    let rom = fs::read(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("roms/7-beep.ch8")).unwrap();
+    let samples = render_wav("beep", rom, 120);
+    assert_eq!(samples.len(), 120 * SAMPLES_PER_FRAME);
+    assert!(samples.iter().any(|&s| s != 0));
+}
+
+#[test]
+fn test_sound_timer_length() {
+    // 6002 - LD V0, 2; F018 - LD ST, V0; 1204 - JP 0x204
+    let samples = render_wav("timer", vec![0x60, 0x02, 0xF0, 0x18, 0x12, 0x04], 10);
+    let frames: Vec<bool> = samples
+        .chunks(SAMPLES_PER_FRAME)
+        .map(|frame| frame.iter().any(|&s| s != 0))
+        .collect();
+    // ST = 2 sounds for two frames, the fade out spills into the third
+    assert_eq!(frames, [true, true, true, false, false, false, false, false, false, false]);
+}
+
+// Remembers the last beep the driver asked for
+struct BeepSink(Rc<Cell<bool>>);
+
+impl AudioSink for BeepSink {
+    fn set_beep(&mut self, on: bool) {
+        self.0.set(on);
+    }
+    fn set_muted(&mut self, _muted: bool) {}
+    fn is_muted(&self) -> bool {
+        false
+    }
+}
+
+#[test]
+fn test_pause_silences_the_beep() {
+    // 603C - LD V0, 60; F018 - LD ST, V0; 1204 - JP 0x204
+    let mut cpu = Cpu::new();
+    cpu.load_rom(vec![0x60, 0x3C, 0xF0, 0x18, 0x12, 0x04]).unwrap();
+    let beep = Rc::new(Cell::new(false));
+    let mut driver = Driver::new(cpu, Box::new(BeepSink(beep.clone())));
+    driver.run_frame().unwrap();
+    assert!(beep.get());
+    driver.set_paused(true);
+    assert!(!beep.get());
+    // Stepping while paused doesn't start it again
+    driver.step().unwrap();
+    assert!(!beep.get());
+    driver.set_paused(false);
+    assert!(beep.get());
+}
+
+#[test]
+fn test_crash_silences_the_beep() {
+    // 603C - LD V0, 60; F018 - LD ST, V0; 6100 - LD V1, 0; 8128 - unknown opcode
+    let mut cpu = Cpu::new();
+    cpu.speed = 1; // the timers tick every cycle
+    cpu.load_rom(vec![0x60, 0x3C, 0xF0, 0x18, 0x61, 0x00, 0x81, 0x28]).unwrap();
+    let beep = Rc::new(Cell::new(false));
+    let mut driver = Driver::new(cpu, Box::new(BeepSink(beep.clone())));
+    for _ in 0..3 {
+        driver.step().unwrap();
+    }
+    assert!(beep.get());
+    assert!(driver.step().is_err());
+    assert!(driver.paused && !beep.get());
+    // Skipping the instruction carries on with the sound timer
+    driver.resume();
+    assert!(beep.get());
+}
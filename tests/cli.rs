@@ -0,0 +1,121 @@
+// The command line, as scripts see it: output and exit codes
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+fn chip8(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_chip8"))
+        .args(args)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .unwrap()
+}
+
+fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("chip8-cli-{}-{}", std::process::id(), name))
+}
+
+#[test]
+fn test_disasm_and_asm_round_trip() {
+    let listing = temp_path("breakout.asm");
+    let rom = temp_path("breakout.ch8");
+    let output = chip8(&["disasm", "roms/breakout.ch8", "-o", listing.to_str().unwrap()]);
+    assert!(output.status.success());
+    let output = chip8(&["asm", listing.to_str().unwrap(), "-o", rom.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert_eq!(fs::read(&rom).unwrap(), fs::read("roms/breakout.ch8").unwrap());
+    fs::remove_file(listing).unwrap();
+    fs::remove_file(rom).unwrap();
+}
+
+#[test]
+fn test_screen_against_golden() {
+    let output = chip8(&["test", "--frames", "60", "--expect", "tests/golden/2-ibm-logo.txt", "roms/2-ibm-logo.ch8"]);
+    assert!(output.status.success());
+    let output = chip8(&["test", "--frames", "60", "--expect", "tests/golden/1-chip8-logo.txt", "roms/2-ibm-logo.ch8"]);
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn test_info() {
+    let output = chip8(&["info", "--speed", "12", "roms/breakout.ch8"]);
+    let text = String::from_utf8(output.stdout).unwrap();
+    assert!(text.contains("Title     Breakout"));
+    assert!(text.contains("Speed     12 instructions per frame"));
+}
+
+#[test]
+fn test_failures_exit_non_zero() {
+    // A missing rom, without and with a subcommand
+    assert_eq!(chip8(&["missing.ch8"]).status.code(), Some(1));
+    assert_eq!(chip8(&["headless", "missing.ch8"]).status.code(), Some(1));
+    // Bad arguments are clap's
+    assert_eq!(chip8(&["headless", "--frames", "lots", "roms/breakout.ch8"]).status.code(), Some(2));
+    assert_eq!(chip8(&["headless", "--speed", "0", "roms/breakout.ch8"]).status.code(), Some(2));
+    // An unknown opcode stops a headless run with the crash report
+    let rom = temp_path("zeros.ch8");
+    fs::write(&rom, [0x00, 0xE0, 0x00, 0x00]).unwrap();
+    let output = chip8(&["headless", rom.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+    let report = String::from_utf8(output.stderr).unwrap();
+    assert!(report.contains("Error: Unknown OpCode: 0x0000\nPC 0x202  Opcode 0000"));
+    assert!(report.contains("  0x200  00E0  CLS\n  0x202  0000  DW 0x0000"));
+    fs::remove_file(rom).unwrap();
+}
+
+#[test]
+fn test_trace() {
+    let trace = temp_path("trace.txt");
+    let output = chip8(&["headless", "--frames", "1", "--trace", trace.to_str().unwrap(), "roms/2-ibm-logo.ch8"]);
+    assert!(output.status.success());
+    let text = fs::read_to_string(&trace).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines.len(), 8);
+    assert_eq!(lines[0].split_whitespace().collect::<Vec<_>>(), ["0", "0x200", "00E0", "CLS"]);
+    fs::remove_file(trace).unwrap();
+}
+
+#[test]
+fn test_bench() {
+    let output = chip8(&["bench", "--cycles", "1000", "roms/2-ibm-logo.ch8"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert!(lines[0].starts_with("Workload") && lines[0].ends_with("recompiler"));
+    assert!(lines.iter().any(|l| l.starts_with("draw ")) && lines.iter().any(|l| l.starts_with("clear ")));
+    assert!(lines.last().unwrap().starts_with("2-ibm-logo") && lines.last().unwrap().ends_with("M/s"));
+    let output = chip8(&["bench", "--cycles", "1000", "--engine", "cached"]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.lines().next().unwrap().ends_with("cached"));
+    // Without roms, the bundled ones are measured
+    assert!(stdout.lines().any(|l| l.starts_with("breakout ")));
+}
+
+#[test]
+fn test_analyze() {
+    let dot = temp_path("keypad.dot");
+    let output = chip8(&["analyze", "roms/6-keypad.ch8", "--dot", dot.to_str().unwrap()]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("Subroutines     4\n"));
+    assert!(stdout.contains("  0x214  LD [I], V1 writes 0x222-0x223\n"));
+    let dot_text = fs::read_to_string(&dot).unwrap();
+    assert!(dot_text.starts_with("digraph rom {") && dot_text.contains("subgraph cluster_29C"));
+    fs::remove_file(dot).unwrap();
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_no_display_is_an_error() {
+    let output = Command::new(env!("CARGO_BIN_EXE_chip8"))
+        .args(["run", "roms/2-ibm-logo.ch8"])
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .env_remove("DISPLAY")
+        .env_remove("WAYLAND_DISPLAY")
+        .env_remove("XDG_RUNTIME_DIR")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8(output.stderr).unwrap().contains("--terminal"));
+}
@@ -0,0 +1,67 @@
+// Headless recordings: every emulated frame must end up in the output
+use chip8::audio::{NullSink, ToneSettings};
+use chip8::cpu::Cpu;
+use chip8::driver::Driver;
+use chip8::palette::Palette;
+use chip8::record::{RecordFormat, Recorder};
+use std::env;
+use std::fs::{self, File};
+use std::path::PathBuf;
+
+fn record(name: &str, format: RecordFormat, frames: u64) -> PathBuf {
+    let path = env::temp_dir().join(format!("chip8-{}-{}", name, std::process::id()));
+    let mut driver = Driver::new(Cpu::new(), Box::new(NullSink::default()));
+    driver.load_file(&PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("roms/2-ibm-logo.ch8")).unwrap();
+    let recorder = Recorder::start(&path, format, &Palette::default(), 2, ToneSettings::default()).unwrap();
+    driver.recorder = Some(recorder);
+    for _ in 0..frames {
+        driver.run_frame().unwrap();
+    }
+    driver.stop_recording().unwrap().unwrap()
+}
+
+#[test]
+fn test_gif_recording() {
+    let path = record("gif", RecordFormat::Gif, 120);
+    let mut decoder = gif::DecodeOptions::new();
+    decoder.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = decoder.read_info(File::open(&path).unwrap()).unwrap();
+    assert_eq!((decoder.width(), decoder.height()), (128, 64));
+    // Identical frames are merged, but the delays still add up to the two seconds recorded
+    let mut frames = 0;
+    let mut delay = 0;
+    while let Some(frame) = decoder.read_next_frame().unwrap() {
+        frames += 1;
+        delay += frame.delay as u32;
+        assert!(frame.delay >= 2);
+    }
+    assert!(frames > 1 && frames < 120);
+    assert_eq!(delay, 200);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_frame_sequence_recording() {
+    let path = record("frames", RecordFormat::Ppm, 30);
+    assert!(path.join("frame-000001.ppm").exists());
+    assert!(path.join("frame-000030.ppm").exists());
+    assert!(!path.join("frame-000031.ppm").exists());
+    let ppm = fs::read(path.join("frame-000030.ppm")).unwrap();
+    assert!(ppm.starts_with(b"P6\n128 64\n255\n"));
+    assert_eq!(ppm.len(), "P6\n128 64\n255\n".len() + 128 * 64 * 3);
+    // One frame of sound for every picture
+    let wav = fs::read(path.join("audio.wav")).unwrap();
+    assert_eq!(wav.len(), 44 + 30 * chip8::audio::SAMPLES_PER_FRAME * 2);
+    fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+fn test_gif_scale_has_to_fit() {
+    let path = env::temp_dir().join(format!("chip8-huge-{}.gif", std::process::id()));
+    // 64 * 1024 pixels is one more than a GIF can be wide
+    let error = Recorder::start(&path, RecordFormat::Gif, &Palette::default(), 1024, ToneSettings::default())
+        .err()
+        .unwrap();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    assert!(!path.exists());
+}
@@ -0,0 +1,153 @@
+// Golden-image tests over the Timendus test suite in roms/
+//
+// Every ROM is run headlessly for a fixed number of frames and the final
+// framebuffer is compared with the one stored in tests/golden/.
+// Run with UPDATE_GOLDEN=1 to rewrite the stored images after an intended change.
+use chip8::audio::NullSink;
+use chip8::cpu::{Cpu, Engine, CHIP_8_HEIGHT, CHIP_8_WIDTH};
+use chip8::driver::Driver;
+use chip8::UPDATE_RATE;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+const CYCLES_PER_FRAME: u64 = UPDATE_RATE / 60;
+
+// A key event sent to the CPU at the start of the given frame
+enum Input {
+    Press(u32, u8),
+    Release(u32, u8),
+}
+
+fn rom_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("roms").join(format!("{}.ch8", name))
+}
+
+fn run_rom(name: &str, frames: u32, script: &[Input]) -> String {
+    let rom = fs::read(rom_path(name)).expect("Failed to read rom!");
+    let mut cpu = Cpu::new();
+    cpu.load_rom(rom).expect("Failed to load rom!");
+    for frame in 0..frames {
+        for input in script {
+            match *input {
+                Input::Press(f, k) if f == frame => cpu.press_key(k),
+                Input::Release(f, k) if f == frame => cpu.release_key(k),
+                _ => {}
+            }
+        }
+        for _ in 0..CYCLES_PER_FRAME {
+            cpu.emulate_cycle().unwrap();
+        }
+    }
+    image(&cpu)
+}
+
+// One line per row, '#' for a lit pixel
+fn image(cpu: &Cpu) -> String {
+    let mut image = String::new();
+    for y in 0..CHIP_8_HEIGHT {
+        for x in 0..CHIP_8_WIDTH {
+            image.push(if cpu.display().get(x, y) { '#' } else { '.' });
+        }
+        image.push('\n');
+    }
+    image
+}
+
+fn check_golden(name: &str, image: &str) {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("golden")
+        .join(format!("{}.txt", name));
+    if env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::write(&path, image).unwrap();
+        return;
+    }
+    let golden = fs::read_to_string(&path).expect("Missing golden image, run with UPDATE_GOLDEN=1");
+    assert!(golden == image, "{} differs from its golden image\nexpected:\n{}\ngot:\n{}", name, golden, image);
+}
+
+#[test]
+fn test_chip8_logo() {
+    let image = run_rom("1-chip8-logo", 60, &[]);
+    check_golden("1-chip8-logo", &image);
+}
+#[test]
+fn test_ibm_logo() {
+    let image = run_rom("2-ibm-logo", 60, &[]);
+    check_golden("2-ibm-logo", &image);
+}
+#[test]
+fn test_corax() {
+    let image = run_rom("3-corax+", 120, &[]);
+    check_golden("3-corax+", &image);
+}
+#[test]
+fn test_flags() {
+    let image = run_rom("4-flags", 120, &[]);
+    check_golden("4-flags", &image);
+}
+#[test]
+fn test_quirks() {
+    // Pick CHIP-8 from the platform menu
+    let script = [Input::Press(60, 0x1), Input::Release(90, 0x1)];
+    let image = run_rom("5-quirks", 900, &script);
+    check_golden("5-quirks", &image);
+}
+#[test]
+fn test_keypad() {
+    // Pick the Fx0A test from the menu, then press and release a key
+    let script = [
+        Input::Press(60, 0x3),
+        Input::Release(62, 0x3),
+        Input::Press(120, 0xA),
+        Input::Release(122, 0xA),
+    ];
+    let image = run_rom("6-keypad", 180, &script);
+    check_golden("6-keypad", &image);
+}
+#[test]
+fn test_beep() {
+    let image = run_rom("7-beep", 600, &[]);
+    check_golden("7-beep", &image);
+}
+#[test]
+fn test_engines_match_golden_images() {
+    // The faster engines have to draw exactly what the interpreter does
+    for (name, frames) in [("1-chip8-logo", 60), ("2-ibm-logo", 60), ("3-corax+", 120), ("4-flags", 120)] {
+        for engine in [Engine::Cached, Engine::Recompiler] {
+            let mut driver = Driver::new(Cpu::new(), Box::new(NullSink::default()));
+            driver.cpu.engine = engine;
+            driver.load_file(&rom_path(name)).unwrap();
+            for _ in 0..frames {
+                driver.run_frame().unwrap();
+            }
+            check_golden(name, &image(&driver.cpu));
+        }
+    }
+}
+#[test]
+fn test_reset_and_reload() {
+    let mut driver = Driver::new(Cpu::new(), Box::new(NullSink::default()));
+    driver.load_file(&rom_path("2-ibm-logo")).unwrap();
+    assert_eq!(driver.rom_sha1(), "e670ac22abbfe46a3bcf98e36ac5a34074c43693");
+    assert_eq!(driver.rom_name().as_deref(), Some("2-ibm-logo"));
+    for _ in 0..60 {
+        driver.run_frame().unwrap();
+    }
+    let first = image(&driver.cpu);
+    // A reset runs the same rom from the start again
+    driver.reset();
+    assert_eq!(driver.cpu.frames(), 0);
+    assert!(!image(&driver.cpu).contains('#'));
+    for _ in 0..60 {
+        driver.run_frame().unwrap();
+    }
+    assert_eq!(image(&driver.cpu), first);
+    driver.reload().unwrap();
+    assert!(!image(&driver.cpu).contains('#'));
+    // A missing file leaves the running rom alone
+    assert!(driver.load_file(&rom_path("missing")).is_err());
+    driver.run_frame().unwrap();
+    driver.reload().unwrap();
+}